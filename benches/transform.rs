@@ -0,0 +1,73 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use rayon_ingest::framework::*;
+use rayon_ingest::transformers::*;
+
+use std::io::{BufRead, Cursor, Read};
+
+const ITEMS: u64 = 10_000;
+
+// Compare a transformer with a nameable iterator type to the same one behind a `BoxIter`,
+// which is what transformers returning closures pay per input item
+fn per_item(c: &mut Criterion) {
+    let mut group = c.benchmark_group("per_item");
+    group.throughput(Throughput::Elements(ITEMS));
+    let identity = Identity::<u64>::default();
+
+    group.bench_function("unboxed", |b| {
+        b.iter(|| {
+            (0..ITEMS)
+                .flat_map(|i| identity.transform(FlowFile::new(i)))
+                .map(|i| black_box(i.data))
+                .sum::<u64>()
+        })
+    });
+
+    group.bench_function("boxed", |b| {
+        b.iter(|| {
+            (0..ITEMS)
+                .flat_map(|i| Box::new(identity.transform(FlowFile::new(i))) as BoxIter<u64>)
+                .map(|i| black_box(i.data))
+                .sum::<u64>()
+        })
+    });
+
+    group.finish();
+}
+
+// Boxing once per file is negligible compared to the work of reading it: the same line
+// iterator with its concrete type and boxed, and `Lines` for reference
+fn per_file(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lines");
+    let lines = Lines::default();
+    let split = |text: &String| {
+        Cursor::new(text.clone())
+            .split(b'\n')
+            .map(|l| FlowFile::new(String::from_utf8(l.unwrap()).unwrap()))
+    };
+
+    for &count in &[10u64, 1_000, 100_000] {
+        let text = "some,line,of,text\n".repeat(count as usize);
+        group.throughput(Throughput::Elements(count));
+        group.bench_with_input(BenchmarkId::new("unboxed", count), &text, |b, text| {
+            b.iter(|| split(text).map(|l| black_box(l.data).len()).sum::<usize>())
+        });
+        group.bench_with_input(BenchmarkId::new("boxed", count), &text, |b, text| {
+            b.iter(|| {
+                let lines = Box::new(split(text)) as BoxIter<String>;
+                lines.map(|l| black_box(l.data).len()).sum::<usize>()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("Lines", count), &text, |b, text| {
+            b.iter(|| {
+                let reader = Box::new(Cursor::new(text.clone())) as Box<dyn Read + Send + Sync>;
+                lines.transform(FlowFile::new(reader)).count()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, per_item, per_file);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+#![allow(clippy::unnecessary_fallible_conversions)]
+
+use csv::StringRecord;
+use rayon_ingest::archive::*;
+use rayon_ingest::framework::*;
+use rayon_ingest::join::*;
+use rayon_ingest::junctions::*;
+use rayon_ingest::partition::*;
+use rayon_ingest::record::*;
+use rayon_ingest::rolling::*;
+use rayon_ingest::text::*;
+use rayon_ingest::transformers::*;
+use std::io::Read;
+use std::path::PathBuf;
+
+use env_logger::Env;
+
+use std::convert::TryFrom;
+
+// the statements are long, but regenerated rather than edited
+#[rustfmt::skip]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // setup logger, DEBUG level by default
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+    let stats = Stats::new();
+
+    let t0 = Glob::try_from(vec![String::from("data/*.tar")])?;
+    let t1 = Untar::<PathBuf>::try_from(vec![])?;
+    let t2 = SplitByExt::<Box<dyn Read + Send + Sync>>::try_from(vec![String::from("csv"), String::from("txt"), String::from("join"), String::from("log")])?;
+    let t3 = CsvRecords::try_from(vec![])?;
+    let t4 = Select::try_from(vec![String::from("id"), String::from("value")])?;
+    let t5 = WriteCsv::<Record>::try_from(vec![String::from("out/values.csv")])?;
+    let t6 = Lines::try_from(vec![])?;
+    let t7 = PartitionedWrite::try_from(vec![String::from("out/lines"), String::from("by=source")])?;
+    let t8 = CsvInnerJoin::try_from(vec![])?;
+    let t9 = ToString::<StringRecord>::try_from(vec![])?;
+    let t10 = Lines::try_from(vec![])?;
+    let t11 = RollingWrite::try_from(vec![String::from("out/joined-{seq}.tar.gz")])?;
+    let t12: std::sync::Arc<dyn ErrorSink> = std::sync::Arc::new(DeadLetter::try_from(vec![String::from("out/errors.txt")])?);
+
+    let f0 = Flow::start_with_errors(t0, t12.clone(), DEFAULT_CAPACITY, &stats);
+    let f1 = f0.transform(t1, default_threads(), DEFAULT_CAPACITY);
+    let mut f2 = f1.split(t2, 4, DEFAULT_CAPACITY).into_iter();
+    let f2_0 = f2.next().unwrap();
+    let f2_1 = f2.next().unwrap();
+    let f2_2 = f2.next().unwrap();
+    let f2_3 = f2.next().unwrap();
+    let f3 = f2_0.transform(t3, default_threads(), DEFAULT_CAPACITY);
+    let f4 = f3.transform(t4, default_threads(), DEFAULT_CAPACITY);
+    let x5 = f4.close(t5, default_threads());
+    let f6 = f2_1.transform(t6, default_threads(), DEFAULT_CAPACITY);
+    let x7 = f6.close(t7, default_threads());
+    let f8 = f2_2.transform(t8, default_threads(), DEFAULT_CAPACITY);
+    let f9 = f8.transform(t9, default_threads(), DEFAULT_CAPACITY);
+    let f10 = f2_3.transform(t10, 2, DEFAULT_CAPACITY);
+    let x11 = Flow::merge(vec![f9, f10], 64)?.close(t11, 1);
+
+    x5.join().and(x7.join()).and(x11.join()).and(t12.finish())?;
+
+    Ok(())
+}
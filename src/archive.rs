@@ -0,0 +1,262 @@
+#[cfg(feature = "zip")]
+use crate::error::Error;
+use crate::framework::*;
+use crate::transformers::decode;
+
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::mpsc::sync_channel;
+use std::time::{Duration, SystemTime};
+
+type Reader = Box<dyn Read + Send + Sync>;
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Archives are read from a path, or from the reader of a file that was unpacked before
+pub trait ArchiveInput: Sized {
+    // A stream of the archive, decompressed
+    fn open(input: FlowFile<Self>) -> Option<FlowFile<Reader>>;
+
+    // A seekable archive, as needed for zip files
+    fn open_seekable(input: FlowFile<Self>) -> Option<FlowFile<Box<dyn ReadSeek + Send>>>;
+}
+
+fn open_path(input: FlowFile<PathBuf>) -> Option<(File, FlowFile<PathBuf>)> {
+    let FlowFile { data, mut meta } = input;
+    meta.add_source(&data.to_string_lossy());
+    meta.set_attribute(PATH, data.to_string_lossy().into_owned());
+
+    match File::open(&data) {
+        Ok(file) => Some((file, FlowFile { data, meta })),
+        Err(e) => {
+            meta.fail(e);
+            None
+        }
+    }
+}
+
+impl ArchiveInput for PathBuf {
+    fn open(input: FlowFile<Self>) -> Option<FlowFile<Reader>> {
+        let (file, FlowFile { data, mut meta }) = open_path(input)?;
+        match decode(file, &data) {
+            Ok((data, codec)) => {
+                if let Some(codec) = codec {
+                    meta.set_attribute(CODEC, codec);
+                }
+                Some(FlowFile { data, meta })
+            }
+            Err(e) => {
+                meta.fail(e);
+                None
+            }
+        }
+    }
+
+    fn open_seekable(input: FlowFile<Self>) -> Option<FlowFile<Box<dyn ReadSeek + Send>>> {
+        let (file, FlowFile { meta, .. }) = open_path(input)?;
+        Some(FlowFile {
+            data: Box::new(file),
+            meta,
+        })
+    }
+}
+
+impl ArchiveInput for Reader {
+    fn open(input: FlowFile<Self>) -> Option<FlowFile<Reader>> {
+        Some(input)
+    }
+
+    // The reader cannot seek, so the whole archive is read into memory
+    fn open_seekable(input: FlowFile<Self>) -> Option<FlowFile<Box<dyn ReadSeek + Send>>> {
+        let FlowFile { mut data, meta } = input;
+        let mut buf = vec![];
+        match data.read_to_end(&mut buf) {
+            Ok(_) => Some(FlowFile {
+                data: Box::new(Cursor::new(buf)),
+                meta,
+            }),
+            Err(e) => {
+                meta.fail(e);
+                None
+            }
+        }
+    }
+}
+
+// The meta of an archive member, its path is appended to the source as `archive.tar!member`
+fn member_meta(
+    meta: &FlowFileMeta,
+    path: &str,
+    size: u64,
+    mtime: Option<SystemTime>,
+) -> FlowFileMeta {
+    let mut meta = meta.clone();
+    meta.add_source(&format!("!{}", path));
+    meta.set_attribute(MEMBER, path);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    meta.set_attribute(FILENAME, name);
+    meta.set_attribute(FILE_SIZE, size);
+    match mtime {
+        Some(mtime) => meta.set_attribute(MTIME, mtime),
+        None => {
+            meta.remove_attribute(MTIME);
+        }
+    }
+    meta
+}
+
+// Emits one reader per file in a tar archive, compressed archives are decoded like `Unpack`
+// does. Members are read into memory one at a time, by a thread per archive.
+pub struct Untar<I> {
+    marker: PhantomData<I>,
+}
+
+impl<I> From<Vec<String>> for Untar<I> {
+    fn from(_args: Vec<String>) -> Self {
+        Self {
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<I: ArchiveInput + Send + Sync + 'static> Transform for Untar<I> {
+    type Input = I;
+    type Output = Reader;
+    type Iter = BoxIter<Self::Output>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { data, meta } = match I::open(input) {
+            Some(input) => input,
+            None => return Box::new(std::iter::empty()),
+        };
+
+        // tar entries borrow the archive, so they are produced on their own thread
+        let (tx, rx) = sync_channel(1);
+        std::thread::spawn(move || {
+            let mut archive = tar::Archive::new(data);
+            let entries = match archive.entries() {
+                Ok(entries) => entries,
+                Err(e) => return meta.fail(e),
+            };
+
+            for entry in entries {
+                let mut entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return meta.fail(e),
+                };
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+
+                let path = match entry.path() {
+                    Ok(path) => path.to_string_lossy().into_owned(),
+                    Err(e) => return meta.fail(e),
+                };
+                let mtime = entry
+                    .header()
+                    .mtime()
+                    .ok()
+                    .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+                let meta = member_meta(&meta, &path, entry.size(), mtime);
+
+                let mut buf = Vec::with_capacity(entry.size() as usize);
+                if let Err(e) = entry.read_to_end(&mut buf) {
+                    return meta.fail(e);
+                }
+                let data = Box::new(Cursor::new(buf)) as Reader;
+                if tx.send(FlowFile { data, meta }).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Box::new(rx.into_iter())
+    }
+}
+
+// Zip times are local times without a zone, they are taken as UTC
+#[cfg(feature = "zip")]
+fn zip_time(t: zip::DateTime) -> SystemTime {
+    // days since the epoch of a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let (y, m, d) = (t.year() as i64, t.month() as i64, t.day() as i64);
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + t.hour() as i64 * 3600 + t.minute() as i64 * 60 + t.second() as i64;
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+// Emits one reader per file in a zip archive. Members are read into memory one at a time.
+#[cfg(feature = "zip")]
+pub struct Unzip<I> {
+    marker: PhantomData<I>,
+}
+
+#[cfg(feature = "zip")]
+impl<I> From<Vec<String>> for Unzip<I> {
+    fn from(_args: Vec<String>) -> Self {
+        Self {
+            marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "zip")]
+impl<I: ArchiveInput + Send + Sync + 'static> Transform for Unzip<I> {
+    type Input = I;
+    type Output = Reader;
+    type Iter = BoxIter<Self::Output>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { data, meta } = match I::open_seekable(input) {
+            Some(input) => input,
+            None => return Box::new(std::iter::empty()),
+        };
+        let mut archive = match zip::ZipArchive::new(data) {
+            Ok(archive) => archive,
+            Err(e) => {
+                meta.fail(Error::Zip(e));
+                return Box::new(std::iter::empty());
+            }
+        };
+
+        let mut index = 0;
+        let members = std::iter::from_fn(move || {
+            while index < archive.len() {
+                let mut file = match archive.by_index(index) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        meta.fail(Error::Zip(e));
+                        return None;
+                    }
+                };
+                index += 1;
+                if !file.is_file() {
+                    continue;
+                }
+
+                let mtime = file.last_modified().map(zip_time);
+                let meta = member_meta(&meta, file.name(), file.size(), mtime);
+
+                let mut buf = Vec::with_capacity(file.size() as usize);
+                if let Err(e) = file.read_to_end(&mut buf) {
+                    meta.fail(e);
+                    return None;
+                }
+                let data = Box::new(Cursor::new(buf)) as Reader;
+                return Some(FlowFile { data, meta });
+            }
+            None
+        });
+
+        Box::new(members)
+    }
+}
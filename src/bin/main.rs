@@ -1,30 +1,29 @@
 use rayon_ingest::framework::*;
+use rayon_ingest::join::CsvInnerJoin;
 use rayon_ingest::transformers::*;
 
-use rayon::iter::ParallelBridge;
-use rayon::prelude::ParallelIterator;
-
 use env_logger::Env;
 
+use std::convert::TryFrom;
+
 fn main() {
     // setup logger, DEBUG level by default
     env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
 
-    let g = Glob::from(vec!["testcase.csv".to_string()]);
-    let u = Unpack {};
-    let t = CsvInnerJoin {};
+    let g = Glob::try_from(vec!["testcase.csv".to_string()]).unwrap();
+    let u = Unpack::default();
+    let t = CsvInnerJoin::default();
     let s = ToString::from(vec![]);
     let o = StdOut {};
 
     let stats = Stats::new();
 
-    g.start()
-        .par_bridge()
-        .flat_map(|i| u.transform(i).par_bridge())
-        .flat_map(|i| t.transform(i).par_bridge())
-        .flat_map(|i| s.transform(i).par_bridge())
-        .for_each(|i| {
-            o.close(i);
-            stats.increment();
-        });
+    // bounded queues between the stages, and the number of threads per stage
+    Flow::start(g, 16, &stats)
+        .transform(u, 1, 16)
+        .transform(t, 4, 1024)
+        .transform(s, 4, 1024)
+        .close(o, 1)
+        .join()
+        .unwrap();
 }
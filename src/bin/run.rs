@@ -0,0 +1,47 @@
+use rayon_ingest::framework::*;
+use rayon_ingest::pipeline::Pipeline;
+use rayon_ingest::registry::Registry;
+
+use std::io::Read;
+
+use env_logger::Env;
+
+fn main() {
+    // setup logger, DEBUG level by default
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+    // read the pipeline from the given file, or from stdin
+    let src = match std::env::args().nth(1) {
+        Some(path) => std::fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("Cannot read {}: {}", path, e);
+            std::process::exit(1)
+        }),
+        None => {
+            let mut src = String::new();
+            std::io::stdin().read_to_string(&mut src).unwrap();
+            src
+        }
+    };
+
+    let registry = Registry::default();
+    let pipeline = Pipeline::parse(&src, &registry).unwrap_or_else(|e| {
+        eprintln!("Invalid pipeline: {}", e);
+        std::process::exit(1)
+    });
+
+    let stats = Stats::new();
+
+    // serve the stats while running, e.g. METRICS_ADDR=127.0.0.1:9898
+    #[cfg(feature = "prometheus")]
+    let _server = std::env::var("METRICS_ADDR").ok().map(|addr| {
+        rayon_ingest::metrics::MetricsServer::serve(&stats, addr).unwrap_or_else(|e| {
+            eprintln!("Cannot serve metrics: {}", e);
+            std::process::exit(1)
+        })
+    });
+
+    if let Err(e) = pipeline.run(&stats) {
+        eprintln!("Pipeline failed: {}", e);
+        std::process::exit(1)
+    }
+}
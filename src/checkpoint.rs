@@ -0,0 +1,193 @@
+use crate::error::Result;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+// Save line progress at most every this many lines, and when a source is done
+const SAVE_INTERVAL: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Done,
+    // lines before this one were processed
+    Line(u64),
+}
+
+// Persistent record of the sources that were processed, so a rerun can skip them.
+//
+// The store is an append-only log with lines `done <source>` and `line <n> <source>`, where
+// the last entry of a source wins. It is compacted when opened.
+pub struct Checkpoint {
+    path: PathBuf,
+    file: Mutex<File>,
+    state: Mutex<HashMap<String, State>>,
+}
+
+// Checkpoints opened by this process, so transformers naming the same store share it
+static OPEN: Mutex<Vec<(PathBuf, Weak<Checkpoint>)>> = Mutex::new(Vec::new());
+
+impl Checkpoint {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Arc<Self>> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut open = OPEN.lock().unwrap();
+        open.retain(|(_, c)| c.strong_count() > 0);
+        if let Some(checkpoint) = open
+            .iter()
+            .find(|(p, _)| *p == path)
+            .and_then(|(_, c)| c.upgrade())
+        {
+            return Ok(checkpoint);
+        }
+
+        let state = load(&path)?;
+
+        // compact, and replace the old log atomically
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp)?;
+        for (source, state) in &state {
+            file.write_all(entry(source, *state).as_bytes())?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+
+        let checkpoint = Arc::new(Self {
+            path: path.clone(),
+            file: Mutex::new(file),
+            state: Mutex::new(state),
+        });
+        open.push((path, Arc::downgrade(&checkpoint)));
+
+        Ok(checkpoint)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn state(&self, source: &str) -> Option<State> {
+        self.state.lock().unwrap().get(source).copied()
+    }
+
+    pub fn is_done(&self, source: &str) -> bool {
+        self.state(source) == Some(State::Done)
+    }
+
+    pub fn complete(&self, source: &str) {
+        self.record(source, State::Done)
+    }
+
+    // Lines of a source that was completed are not recorded again
+    pub fn progress(&self, source: &str, line: u64) {
+        if !self.is_done(source) {
+            self.record(source, State::Line(line))
+        }
+    }
+
+    fn record(&self, source: &str, state: State) {
+        self.state.lock().unwrap().insert(source.to_string(), state);
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(entry(source, state).as_bytes()) {
+            log::error!("cannot write checkpoint {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn entry(source: &str, state: State) -> String {
+    match state {
+        State::Done => format!("done {}\n", source),
+        State::Line(line) => format!("line {} {}\n", line, source),
+    }
+}
+
+fn load(path: &Path) -> Result<HashMap<String, State>> {
+    let mut state = HashMap::new();
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+        Err(e) => return Err(e.into()),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("done"), Some(source)) => {
+                state.insert(source.to_string(), State::Done);
+            }
+            (Some("line"), Some(rest)) => {
+                let mut parts = rest.splitn(2, ' ');
+                if let (Some(Ok(n)), Some(source)) = (parts.next().map(str::parse), parts.next()) {
+                    state.insert(source.to_string(), State::Line(n));
+                }
+            }
+            // a crash may leave a partial last line
+            _ => log::warn!("ignoring checkpoint entry `{}`", line),
+        }
+    }
+
+    Ok(state)
+}
+
+// Tracks the lines of a source that are processed. Lines may finish out of order, so the
+// progress is the first line that is not processed yet. A line that failed is never complete,
+// so the progress stops before it and a resumed run retries it.
+pub(crate) struct Progress {
+    checkpoint: Arc<Checkpoint>,
+    source: String,
+    lines: Mutex<LineState>,
+}
+
+struct LineState {
+    next: u64,
+    saved: u64,
+    pending: BTreeSet<u64>,
+}
+
+impl Progress {
+    pub(crate) fn new(checkpoint: Arc<Checkpoint>, source: &str, next: u64) -> Arc<Self> {
+        Arc::new(Self {
+            checkpoint,
+            source: source.to_string(),
+            lines: Mutex::new(LineState {
+                next,
+                saved: next,
+                pending: BTreeSet::new(),
+            }),
+        })
+    }
+
+    pub(crate) fn complete(&self, line: u64) {
+        let mut lines = self.lines.lock().unwrap();
+        lines.pending.insert(line);
+        loop {
+            let next = lines.next;
+            if !lines.pending.remove(&next) {
+                break;
+            }
+            lines.next += 1;
+        }
+
+        if lines.next >= lines.saved + SAVE_INTERVAL {
+            lines.saved = lines.next;
+            self.checkpoint.progress(&self.source, lines.next);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        let lines = self.lines.get_mut().unwrap();
+        if lines.next > lines.saved {
+            self.checkpoint.progress(&self.source, lines.next);
+        }
+    }
+}
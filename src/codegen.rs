@@ -0,0 +1,232 @@
+use crate::framework::short_type_name;
+use crate::pipeline::{order, parse, resolve, PipelineError, Step};
+use crate::registry::{Entry, Kind, Registry};
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+const HEADER: &str = r#"
+use env_logger::Env;
+
+use std::convert::TryFrom;
+
+// the statements are long, but regenerated rather than edited
+#[rustfmt::skip]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // setup logger, DEBUG level by default
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+    let stats = Stats::new();
+"#;
+
+fn quote(s: &str) -> String {
+    let mut quoted = "String::from(\"".to_string();
+    for c in s.chars() {
+        if c == '"' {
+            quoted.push_str("\\\"");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push_str("\")");
+
+    quoted
+}
+
+// Paths from `type_name` can have modules that are not public
+fn path(name: &str) -> String {
+    name.replace("alloc::", "std::")
+        .replace("csv::string_record::", "csv::")
+        .replace("serde_json::value::", "serde_json::")
+}
+
+// The transformer type without parameters, e.g. `rayon_ingest::archive::Untar`
+fn base(entry: &Entry) -> &'static str {
+    let name = entry.ty.name();
+    &name[..name.find('<').unwrap_or(name.len())]
+}
+
+// The parameters of a generic transformer, e.g. `<std::path::PathBuf>`
+fn params(entry: &Entry) -> &'static str {
+    &entry.ty.name()[base(entry).len()..]
+}
+
+// The imports for the generated code: the traits, the modules of the steps, and the types in
+// the parameters of generic steps, but for those in the prelude
+fn imports(entries: &[Entry]) -> BTreeSet<String> {
+    let mut modules = BTreeSet::new();
+    modules.insert("rayon_ingest::framework".to_string());
+    for entry in entries {
+        let name = base(entry);
+        modules.insert(path(&name[..name.rfind("::").unwrap_or(0)]));
+    }
+
+    let prelude = ["Box", "String", "Vec", "Send", "Sync"];
+    let mut types = BTreeSet::new();
+    for entry in entries {
+        for name in params(entry).split(|c: char| !c.is_alphanumeric() && c != '_' && c != ':') {
+            let path = path(name);
+            match path.rsplit_once("::") {
+                Some((module, short)) if !prelude.contains(&short) && !modules.contains(module) => {
+                    types.insert(path);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let mut imports: BTreeSet<_> = modules.into_iter().map(|m| format!("{}::*", m)).collect();
+    imports.extend(types);
+    imports
+}
+
+// The transformer type as an expression, e.g. `Untar::<PathBuf>`. Generic transformers need
+// their parameters, which cannot be inferred before their first use.
+fn constructor(step: &Step, entry: &Entry) -> String {
+    match params(entry) {
+        "" => step.name.clone(),
+        params => format!("{}::{}", step.name, short_type_name(params)),
+    }
+}
+
+// The input flow of step `n`, merging the flows of all steps leading to it
+fn input(inputs: &mut [Vec<String>], steps: &[Step], n: usize) -> String {
+    let flows = std::mem::take(&mut inputs[n]);
+    match flows.as_slice() {
+        [flow] => flow.clone(),
+        _ => format!(
+            "Flow::merge(vec![{}], {})?",
+            flows.join(", "),
+            capacity(&steps[n])
+        ),
+    }
+}
+
+fn threads(step: &Step) -> String {
+    match step.threads {
+        Some(n) => n.to_string(),
+        None => "default_threads()".to_string(),
+    }
+}
+
+fn capacity(step: &Step) -> String {
+    match step.capacity {
+        Some(n) => n.to_string(),
+        None => "DEFAULT_CAPACITY".to_string(),
+    }
+}
+
+// Print the statements that build the flow of every step, after the flows of its inputs. Sources
+// give their items the error sink `errors`, if any, and the sinks are joined at the end.
+fn print_flows(out: &mut String, steps: &[Step], kinds: &[Kind], errors: Option<usize>) {
+    let mut inputs = vec![vec![]; steps.len()];
+    let mut executors = vec![];
+
+    for i in order(steps).unwrap() {
+        let step = &steps[i];
+        let flow = match kinds[i] {
+            _ if step.on_error => continue,
+            Kind::Start => match errors {
+                Some(e) => format!(
+                    "Flow::start_with_errors(t{}, t{}.clone(), {}, &stats)",
+                    i,
+                    e,
+                    capacity(step)
+                ),
+                None => format!("Flow::start(t{}, {}, &stats)", i, capacity(step)),
+            },
+            Kind::Transform => format!(
+                "{}.transform(t{}, {}, {})",
+                input(&mut inputs, steps, i),
+                i,
+                threads(step),
+                capacity(step)
+            ),
+            Kind::Junction => {
+                let split = format!(
+                    "{}.split(t{}, {}, {})",
+                    input(&mut inputs, steps, i),
+                    i,
+                    step.next.len(),
+                    capacity(step)
+                );
+                writeln!(out, "    let mut f{} = {}.into_iter();", i, split).unwrap();
+                for (branch, &n) in step.next.iter().enumerate() {
+                    writeln!(out, "    let f{}_{} = f{}.next().unwrap();", i, branch, i).unwrap();
+                    inputs[n].push(format!("f{}_{}", i, branch));
+                }
+                continue;
+            }
+            Kind::Close => {
+                let close = format!(
+                    "{}.close(t{}, {})",
+                    input(&mut inputs, steps, i),
+                    i,
+                    threads(step)
+                );
+                writeln!(out, "    let x{} = {};", i, close).unwrap();
+                executors.push(i);
+                continue;
+            }
+        };
+        writeln!(out, "    let f{} = {};", i, flow).unwrap();
+        inputs[step.next[0]].push(format!("f{}", i));
+    }
+
+    // every sink is finished, also when an earlier one fails, and then the error sink
+    let mut joins: Vec<_> = executors.iter().map(|i| format!("x{}.join()", i)).collect();
+    if let Some(e) = errors {
+        joins.push(format!("t{}.finish()", e));
+    }
+    let result = joins.iter().skip(1).fold(joins[0].clone(), |result, join| {
+        format!("{}.and({})", result, join)
+    });
+    writeln!(out).unwrap();
+    writeln!(out, "    {}?;", result).unwrap();
+}
+
+// Generate the source of a program running the pipeline without the registry, so the compiler
+// sees the concrete types of all steps.
+pub fn generate(src: &str, registry: &Registry) -> Result<String, PipelineError> {
+    let steps = parse(src)?;
+    let entries = resolve(&steps, registry)?;
+    let kinds: Vec<_> = entries.iter().map(|e| e.kind).collect();
+
+    // steps without arguments may not fail, but all steps are built the same way
+    let mut out = String::new();
+    writeln!(out, "#![allow(clippy::unnecessary_fallible_conversions)]\n").unwrap();
+    for import in imports(&entries) {
+        writeln!(out, "use {};", import).unwrap();
+    }
+    writeln!(out, "{}", HEADER).unwrap();
+
+    for (i, (step, entry)) in steps.iter().zip(&entries).enumerate() {
+        let args = step
+            .args
+            .iter()
+            .map(|s| quote(s))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let ty = constructor(step, entry);
+        let build = format!("{}::try_from(vec![{}])?", ty, args);
+        if step.on_error {
+            let arc = "std::sync::Arc";
+            let line = format!(
+                "let t{}: {}<dyn ErrorSink> = {}::new({});",
+                i, arc, arc, build
+            );
+            writeln!(out, "    {}", line).unwrap();
+        } else {
+            writeln!(out, "    let t{} = {};", i, build).unwrap();
+        }
+    }
+    let errors = steps.iter().position(|s| s.on_error);
+
+    writeln!(out).unwrap();
+    print_flows(&mut out, &steps, &kinds, errors);
+
+    writeln!(out).unwrap();
+    writeln!(out, "    Ok(())").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
@@ -0,0 +1,361 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::record::Record;
+use crate::transformers::options;
+
+use arrow_array::builder::{BooleanBuilder, Float64Builder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColumnType {
+    Utf8,
+    Int64,
+    Float64,
+    Bool,
+}
+
+struct Column {
+    name: String,
+    kind: ColumnType,
+}
+
+// Parses `id:int64,value,score:float64`, columns are utf8 unless given otherwise
+fn parse_schema(value: &str) -> Result<Vec<Column>> {
+    value
+        .split(',')
+        .map(|column| {
+            let (name, kind) = match column.split_once(':') {
+                Some((name, kind)) => (name, kind),
+                None => (column, "utf8"),
+            };
+            let kind = match kind {
+                "utf8" => ColumnType::Utf8,
+                "int64" => ColumnType::Int64,
+                "float64" => ColumnType::Float64,
+                "bool" => ColumnType::Bool,
+                _ => {
+                    let msg = format!(
+                        "column `{}` needs type utf8, int64, float64 or bool, got `{}`",
+                        name, kind
+                    );
+                    return Err(Error::Args(msg));
+                }
+            };
+            Ok(Column {
+                name: name.to_string(),
+                kind,
+            })
+        })
+        .collect()
+}
+
+fn arrow_schema(columns: &[Column]) -> SchemaRef {
+    let fields: Vec<_> = columns
+        .iter()
+        .map(|c| {
+            let kind = match c.kind {
+                ColumnType::Utf8 => DataType::Utf8,
+                ColumnType::Int64 => DataType::Int64,
+                ColumnType::Float64 => DataType::Float64,
+                ColumnType::Bool => DataType::Boolean,
+            };
+            arrow_schema::Field::new(c.name.as_str(), kind, true)
+        })
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+// A parsed value, empty fields are null unless the column is utf8
+enum Cell<'a> {
+    Null,
+    Utf8(&'a str),
+    Int64(i64),
+    Float64(f64),
+    Bool(bool),
+}
+
+fn parse_cell<'a>(column: &Column, value: &'a str) -> Result<Cell<'a>> {
+    let cell = match column.kind {
+        ColumnType::Utf8 => Some(Cell::Utf8(value)),
+        _ if value.is_empty() => Some(Cell::Null),
+        ColumnType::Int64 => value.parse().ok().map(Cell::Int64),
+        ColumnType::Float64 => value.parse().ok().map(Cell::Float64),
+        ColumnType::Bool => value.parse().ok().map(Cell::Bool),
+    };
+
+    cell.ok_or_else(|| {
+        Error::Parse(format!(
+            "`{}` is not a valid {:?} for column `{}`",
+            value, column.kind, column.name
+        ))
+    })
+}
+
+enum Builder {
+    Utf8(StringBuilder),
+    Int64(Int64Builder),
+    Float64(Float64Builder),
+    Bool(BooleanBuilder),
+}
+
+impl Builder {
+    fn new(kind: ColumnType) -> Self {
+        match kind {
+            ColumnType::Utf8 => Builder::Utf8(StringBuilder::new()),
+            ColumnType::Int64 => Builder::Int64(Int64Builder::new()),
+            ColumnType::Float64 => Builder::Float64(Float64Builder::new()),
+            ColumnType::Bool => Builder::Bool(BooleanBuilder::new()),
+        }
+    }
+
+    fn append(&mut self, cell: Cell) {
+        match (self, cell) {
+            (Builder::Utf8(b), Cell::Utf8(v)) => b.append_value(v),
+            (Builder::Int64(b), Cell::Int64(v)) => b.append_value(v),
+            (Builder::Float64(b), Cell::Float64(v)) => b.append_value(v),
+            (Builder::Bool(b), Cell::Bool(v)) => b.append_value(v),
+            (Builder::Utf8(b), _) => b.append_null(),
+            (Builder::Int64(b), _) => b.append_null(),
+            (Builder::Float64(b), _) => b.append_null(),
+            (Builder::Bool(b), _) => b.append_null(),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Builder::Utf8(b) => Arc::new(b.finish()),
+            Builder::Int64(b) => Arc::new(b.finish()),
+            Builder::Float64(b) => Arc::new(b.finish()),
+            Builder::Bool(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+struct State {
+    // taken from the header of the first record without `schema=`
+    columns: Option<(Vec<Column>, SchemaRef)>,
+    builders: Vec<Builder>,
+    buffered: usize,
+    writer: Option<ArrowWriter<File>>,
+    // rows in the current file, including the buffered ones
+    file_rows: usize,
+    seq: usize,
+}
+
+// Writes CSV records to Parquet files, in row groups of `row_group` rows (default 65536).
+//
+// The first argument is the path, options are given as `key=value`:
+// schema=id:int64,value,score:float64 selects and types the columns (utf8, int64, float64 or
+// bool), by default every column of the first record is written as utf8.
+// rows_per_file=N starts a new file after N rows, the path then needs a `{seq}` placeholder,
+// e.g. `out-{seq}.parquet`.
+// compression=snappy (default) or none.
+//
+// Records whose fields do not parse as the column type are failed. The last file is completed
+// when the flow ends.
+pub struct WriteParquet<A> {
+    path: String,
+    row_group: usize,
+    rows_per_file: Option<usize>,
+    properties: WriterProperties,
+    state: Mutex<State>,
+    marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for WriteParquet<A> {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        if args.is_empty() || args[0].contains('=') {
+            return Err(Error::Args("WriteParquet needs an output path".into()));
+        }
+        let path = args.remove(0);
+
+        let keys = ["schema", "row_group", "rows_per_file", "compression"];
+        let options = options("WriteParquet", &args, &keys)?;
+        let number = |key: &str| {
+            options
+                .get(key)
+                .map(|v| match v.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(n),
+                    _ => Err(Error::Args(format!(
+                        "`{}` needs a positive number, got `{}`",
+                        key, v
+                    ))),
+                })
+                .transpose()
+        };
+
+        let row_group = number("row_group")?.unwrap_or(65536);
+        let rows_per_file = number("rows_per_file")?;
+        if rows_per_file.is_some() && !path.contains("{seq}") {
+            return Err(Error::Args(
+                "`rows_per_file` needs a `{seq}` in the path".into(),
+            ));
+        }
+
+        let compression = match options.get("compression").map(String::as_str) {
+            None | Some("snappy") => Compression::SNAPPY,
+            Some("none") => Compression::UNCOMPRESSED,
+            Some(other) => {
+                let msg = format!("`compression` needs snappy or none, got `{}`", other);
+                return Err(Error::Args(msg));
+            }
+        };
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(row_group)
+            .set_compression(compression)
+            .build();
+
+        let columns = match options.get("schema") {
+            Some(schema) => {
+                let columns = parse_schema(schema)?;
+                let schema = arrow_schema(&columns);
+                Some((columns, schema))
+            }
+            None => None,
+        };
+        let builders = match &columns {
+            Some((columns, _)) => columns.iter().map(|c| Builder::new(c.kind)).collect(),
+            None => vec![],
+        };
+
+        Ok(Self {
+            path,
+            row_group,
+            rows_per_file,
+            properties,
+            state: Mutex::new(State {
+                columns,
+                builders,
+                buffered: 0,
+                writer: None,
+                file_rows: 0,
+                seq: 0,
+            }),
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<A> WriteParquet<A> {
+    // Write the buffered rows as a record batch, to the current file or a new one
+    fn flush(&self, state: &mut State) -> Result<()> {
+        if state.buffered == 0 {
+            return Ok(());
+        }
+        let schema = match &state.columns {
+            Some((_, schema)) => schema.clone(),
+            None => return Ok(()),
+        };
+
+        let arrays = state.builders.iter_mut().map(Builder::finish).collect();
+        state.buffered = 0;
+        let batch = RecordBatch::try_new(schema.clone(), arrays)
+            .map_err(parquet::errors::ParquetError::from)?;
+
+        if state.writer.is_none() {
+            let path = self.path.replace("{seq}", &state.seq.to_string());
+            let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+            let writer = ArrowWriter::try_new(file, schema, Some(self.properties.clone()))?;
+            state.writer = Some(writer);
+            state.seq += 1;
+        }
+        state.writer.as_mut().unwrap().write(&batch)?;
+
+        Ok(())
+    }
+
+    // Flush, and complete the current file
+    fn close_file(&self, state: &mut State) -> Result<()> {
+        let flushed = self.flush(state);
+        state.file_rows = 0;
+        if let Some(writer) = state.writer.take() {
+            writer.close()?;
+        }
+        flushed
+    }
+}
+
+impl<A: Into<Record> + Send + Sync + 'static> CloseTransform for WriteParquet<A> {
+    type Input = A;
+
+    fn close(&self, input: FlowFile<Self::Input>) {
+        let FlowFile { data, meta } = input;
+        let record: Record = data.into();
+
+        let mut state = self.state.lock().unwrap();
+        if state.columns.is_none() {
+            let columns: Vec<_> = (0..record.len())
+                .map(|i| Column {
+                    name: match record.headers().get(i) {
+                        Some(name) => name.to_string(),
+                        None => i.to_string(),
+                    },
+                    kind: ColumnType::Utf8,
+                })
+                .collect();
+            state.builders = columns.iter().map(|c| Builder::new(c.kind)).collect();
+            let schema = arrow_schema(&columns);
+            state.columns = Some((columns, schema));
+        }
+
+        let (columns, _) = state.columns.as_ref().unwrap();
+        let cells: std::result::Result<Vec<_>, _> = columns
+            .iter()
+            .map(|c| match record.get(&c.name) {
+                Some(value) => parse_cell(c, value),
+                None => Err(Error::UnknownColumn(c.name.clone())),
+            })
+            .collect();
+        let cells = match cells {
+            Ok(cells) => cells,
+            Err(e) => {
+                let raw = record.values().iter().collect::<Vec<_>>().join(",");
+                meta.fail_with_data(e, raw);
+                return;
+            }
+        };
+
+        let state = &mut *state;
+        for (builder, cell) in state.builders.iter_mut().zip(cells) {
+            builder.append(cell);
+        }
+        state.buffered += 1;
+        state.file_rows += 1;
+
+        let result = if Some(state.file_rows) == self.rows_per_file {
+            self.close_file(state)
+        } else if state.buffered >= self.row_group {
+            self.flush(state)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            meta.fail(e);
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.close_file(&mut state)
+    }
+}
+
+impl<A> Drop for WriteParquet<A> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.close_file(&mut state) {
+            log::error!("cannot complete {}: {}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::convert::Infallible;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Csv(csv::Error),
+    Pattern(glob::PatternError),
+    Utf8(String),
+    UnknownColumn(String),
+    Parse(String),
+    NoBranch(u8),
+    Args(String),
+    Codec(&'static str),
+    #[cfg(feature = "zip")]
+    Zip(zip::result::ZipError),
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet::errors::ParquetError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Csv(e) => write!(f, "{}", e),
+            Error::Pattern(e) => write!(f, "bad glob pattern: {}", e),
+            Error::Utf8(msg) => write!(f, "{}", msg),
+            Error::UnknownColumn(name) => write!(f, "unknown column `{}`", name),
+            Error::Parse(msg) => write!(f, "{}", msg),
+            Error::NoBranch(i) => write!(f, "no branch {}", i),
+            Error::Args(msg) => write!(f, "bad arguments: {}", msg),
+            Error::Codec(codec) => write!(f, "{} compression is not enabled", codec),
+            #[cfg(feature = "zip")]
+            Error::Zip(e) => write!(f, "{}", e),
+            #[cfg(feature = "json")]
+            Error::Json(e) => write!(f, "{}", e),
+            #[cfg(feature = "parquet")]
+            Error::Parquet(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Csv(e) => Some(e),
+            Error::Pattern(e) => Some(e),
+            #[cfg(feature = "zip")]
+            Error::Zip(e) => Some(e),
+            #[cfg(feature = "json")]
+            Error::Json(e) => Some(e),
+            #[cfg(feature = "parquet")]
+            Error::Parquet(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Csv(e)
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(e: glob::PatternError) -> Self {
+        Error::Pattern(e)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Error::Parquet(e)
+    }
+}
+
+// Transformers that cannot fail to construct implement `From<Vec<String>>`, which makes
+// their `TryFrom` error `Infallible`.
+impl From<Infallible> for Error {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
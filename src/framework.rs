@@ -1,6 +1,15 @@
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use crate::error::{Error, Result};
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug)]
 pub struct FlowFile<T> {
@@ -17,10 +26,87 @@ impl<T> FlowFile<T> {
     }
 }
 
+// Well known attribute keys
+pub const FILENAME: &str = "filename";
+pub const PATH: &str = "path";
+pub const FILE_SIZE: &str = "file.size";
+pub const MTIME: &str = "file.mtime";
+pub const LINE: &str = "line";
+pub const CODEC: &str = "codec";
+pub const MEMBER: &str = "member";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Attribute {
+    Str(String),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    Time(SystemTime),
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attribute::Str(v) => write!(f, "{}", v),
+            Attribute::Int(v) => write!(f, "{}", v),
+            Attribute::UInt(v) => write!(f, "{}", v),
+            Attribute::Bool(v) => write!(f, "{}", v),
+            Attribute::Time(v) => {
+                let secs = v.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                write!(f, "{}", secs.as_secs())
+            }
+        }
+    }
+}
+
+impl From<&str> for Attribute {
+    fn from(v: &str) -> Self {
+        Attribute::Str(v.to_string())
+    }
+}
+
+impl From<String> for Attribute {
+    fn from(v: String) -> Self {
+        Attribute::Str(v)
+    }
+}
+
+impl From<i64> for Attribute {
+    fn from(v: i64) -> Self {
+        Attribute::Int(v)
+    }
+}
+
+impl From<u64> for Attribute {
+    fn from(v: u64) -> Self {
+        Attribute::UInt(v)
+    }
+}
+
+impl From<bool> for Attribute {
+    fn from(v: bool) -> Self {
+        Attribute::Bool(v)
+    }
+}
+
+impl From<SystemTime> for Attribute {
+    fn from(v: SystemTime) -> Self {
+        Attribute::Time(v)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FlowFileMeta {
     source: String,
-    failed: Option<&'static AtomicBool>,
+    // shared with the completions of the items this one derives from
+    failed: Option<Arc<Failed>>,
+    errors: Option<Arc<dyn ErrorSink>>,
+    // Shared between the clones of a meta until one of them changes it, because transformers
+    // like `Lines` clone the meta of the input for every item they emit.
+    attributes: Arc<BTreeMap<String, Attribute>>,
+    // Dropped with the last clone of the meta, i.e. once everything derived from this item
+    // has been processed
+    guard: Option<Arc<dyn Any + Send + Sync>>,
 }
 
 impl FlowFileMeta {
@@ -28,6 +114,9 @@ impl FlowFileMeta {
         FlowFileMeta {
             source: String::new(),
             failed: None,
+            errors: None,
+            attributes: Arc::new(BTreeMap::new()),
+            guard: None,
         }
     }
 
@@ -39,62 +128,537 @@ impl FlowFileMeta {
         self.source.push_str(s)
     }
 
+    // Keep `guard` until the last clone of the meta is dropped. Earlier guards are kept too, so
+    // e.g. a file is only complete after its lines.
+    pub fn add_guard(&mut self, guard: Arc<dyn Any + Send + Sync>) {
+        self.guard = match self.guard.take() {
+            Some(previous) => Some(Arc::new((guard, previous))),
+            None => Some(guard),
+        };
+    }
+
+    // Run `on_success` or `on_failure` once everything derived from this item has been
+    // processed, depending on whether any of it was marked failed
+    pub fn on_complete<F1, F2>(&mut self, on_success: F1, on_failure: F2)
+    where
+        F1: FnOnce() + Send + Sync + 'static,
+        F2: FnOnce() + Send + Sync + 'static,
+    {
+        // failures are also those of the items this one derives from
+        let failed = Arc::new(Failed {
+            flag: AtomicBool::new(false),
+            parent: self.failed.take(),
+        });
+        self.failed = Some(failed.clone());
+        self.add_guard(Arc::new(Completion {
+            failed,
+            on_success: Some(on_success),
+            on_failure: Some(on_failure),
+        }));
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<&Attribute> {
+        self.attributes.get(key)
+    }
+
+    pub fn attributes(&self) -> &BTreeMap<String, Attribute> {
+        &self.attributes
+    }
+
+    pub fn set_attribute<V: Into<Attribute>>(&mut self, key: &str, value: V) {
+        Arc::make_mut(&mut self.attributes).insert(key.to_string(), value.into());
+    }
+
+    pub fn remove_attribute(&mut self, key: &str) -> Option<Attribute> {
+        if !self.attributes.contains_key(key) {
+            return None;
+        }
+        Arc::make_mut(&mut self.attributes).remove(key)
+    }
+
     pub fn mark_failed(&self) {
         if let Some(failed) = &self.failed {
-            failed.store(true, Ordering::SeqCst);
+            failed.set();
+        }
+    }
+
+    pub fn set_error_sink(&mut self, errors: Arc<dyn ErrorSink>) {
+        self.errors = Some(errors);
+    }
+
+    // Mark the item failed and hand the error to the error sink, or log it when there is none
+    pub fn fail<E: Into<Error>>(&self, error: E) {
+        self.fail_inner(error.into(), None)
+    }
+
+    // Like `fail`, but keep the raw input that could not be processed, so it can be replayed
+    pub fn fail_with_data<E: Into<Error>>(&self, error: E, data: String) {
+        self.fail_inner(error.into(), Some(data))
+    }
+
+    fn fail_inner(&self, error: Error, data: Option<String>) {
+        self.mark_failed();
+        STAGE.with(|s| {
+            if let Some(stage) = &*s.borrow() {
+                stage.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let failure = FlowFile {
+            data: Failure { error, data },
+            meta: self.clone(),
+        };
+
+        match &self.errors {
+            Some(errors) => errors.fail(failure),
+            None => log::error!("Failure in {}: {}", self.source, failure.data.error),
+        }
+    }
+}
+
+// Whether an item, or anything derived from it, failed
+#[derive(Debug)]
+struct Failed {
+    flag: AtomicBool,
+    // the item it derives from, which failed as well
+    parent: Option<Arc<Failed>>,
+}
+
+impl Failed {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            flag: AtomicBool::new(false),
+            parent: None,
+        })
+    }
+
+    fn set(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        if let Some(parent) = &self.parent {
+            parent.set();
+        }
+    }
+
+    fn get(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+struct Completion<F1: FnOnce(), F2: FnOnce()> {
+    failed: Arc<Failed>,
+    on_success: Option<F1>,
+    on_failure: Option<F2>,
+}
+
+impl<F1: FnOnce(), F2: FnOnce()> Drop for Completion<F1, F2> {
+    fn drop(&mut self) {
+        if self.failed.get() {
+            if let Some(f) = self.on_failure.take() {
+                f()
+            }
+        } else if let Some(f) = self.on_success.take() {
+            f()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub error: Error,
+    pub data: Option<String>,
+}
+
+pub trait ErrorSink: Send + Sync {
+    fn fail(&self, input: FlowFile<Failure>);
+
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<C: CloseTransform<Input = Failure> + Send + Sync> ErrorSink for C {
+    fn fail(&self, input: FlowFile<Failure>) {
+        self.close(input)
+    }
+
+    fn finish(&self) -> Result<()> {
+        CloseTransform::finish(self)
+    }
+}
+
+impl fmt::Debug for dyn ErrorSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorSink")
+    }
+}
+
+// The type name without module paths, e.g. `Box<dyn Read + Send + Sync>`
+pub fn short_type_name(name: &str) -> String {
+    let mut short = String::with_capacity(name.len());
+    for c in name.chars() {
+        short.push(c);
+        if short.ends_with("::") {
+            short.truncate(short.len() - 2);
+            while short.ends_with(|c: char| c.is_alphanumeric() || c == '_') {
+                short.pop();
+            }
+        }
+    }
+    short
+}
+
+// Size of the payload of a flow file, for the types where that is known
+pub fn byte_size(data: &dyn Any) -> u64 {
+    if let Some(s) = data.downcast_ref::<String>() {
+        s.len() as u64
+    } else if let Some(v) = data.downcast_ref::<Vec<u8>>() {
+        v.len() as u64
+    } else if let Some(r) = data.downcast_ref::<csv::StringRecord>() {
+        r.as_slice().len() as u64
+    } else if let Some(r) = data.downcast_ref::<crate::record::Record>() {
+        r.values().as_slice().len() as u64
+    } else if let Some(b) = data.downcast_ref::<Box<dyn Any + Send>>() {
+        byte_size(&**b)
+    } else {
+        0
+    }
+}
+
+const BUCKETS: usize = 32;
+
+// Latency histogram with power of two buckets, bucket `k` counts latencies below 2^k micros
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: Default::default(),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    // Upper bound of the bucket containing quantile `q`
+    pub fn quantile(&self, q: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::from_secs(0);
+        }
+        let rank = ((count as f64) * q).ceil().max(1.) as u64;
+
+        let mut seen = 0;
+        for (k, b) in self.buckets.iter().enumerate() {
+            seen += b.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Duration::from_micros(1 << k);
+            }
+        }
+        Duration::from_micros(1 << (BUCKETS - 1))
+    }
+
+    // Cumulative counts per bucket upper bound
+    pub fn cumulative(&self) -> Vec<(Duration, u64)> {
+        let mut seen = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(k, b)| {
+                seen += b.load(Ordering::Relaxed);
+                (Duration::from_micros(1 << k), seen)
+            })
+            .collect()
+    }
+}
+
+thread_local! {
+    // The stage running on this thread, so failures can be attributed to it
+    static STAGE: RefCell<Option<Arc<StageStats>>> = const { RefCell::new(None) };
+}
+
+pub struct StageStats {
+    name: String,
+    items_in: AtomicU64,
+    items_out: AtomicU64,
+    failures: AtomicU64,
+    bytes: AtomicU64,
+    nanos: AtomicU64,
+    latency: Histogram,
+}
+
+impl StageStats {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            items_in: AtomicU64::new(0),
+            items_out: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            nanos: AtomicU64::new(0),
+            latency: Histogram::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn items_in(&self) -> u64 {
+        self.items_in.load(Ordering::Relaxed)
+    }
+
+    pub fn items_out(&self) -> u64 {
+        self.items_out.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn time(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    // Run `f` on behalf of this stage, and return how long it took
+    fn enter<R>(self: &Arc<Self>, f: impl FnOnce() -> R) -> (R, Duration) {
+        let start = Instant::now();
+        let previous = STAGE.with(|s| s.replace(Some(self.clone())));
+        let result = f();
+        STAGE.with(|s| s.replace(previous));
+        (result, start.elapsed())
+    }
+
+    // Count the time spent producing the items of the iterator, which is where lazy
+    // transformers do their work
+    pub fn transform<I>(self: &Arc<Self>, f: impl FnOnce() -> I) -> StageIter<I> {
+        self.items_in.fetch_add(1, Ordering::Relaxed);
+        let (iter, elapsed) = self.enter(f);
+
+        StageIter {
+            iter,
+            stage: self.clone(),
+            elapsed,
+        }
+    }
+
+    pub fn split<T: 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce() -> (u8, FlowFile<T>),
+    ) -> (u8, FlowFile<T>) {
+        self.items_in.fetch_add(1, Ordering::Relaxed);
+        let ((branch, output), elapsed) = self.enter(f);
+        self.items_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(byte_size(&output.data), Ordering::Relaxed);
+        self.record(elapsed);
+        (branch, output)
+    }
+
+    pub fn close<T: 'static>(self: &Arc<Self>, input: FlowFile<T>, f: impl FnOnce(FlowFile<T>)) {
+        self.items_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(byte_size(&input.data), Ordering::Relaxed);
+        let ((), elapsed) = self.enter(|| f(input));
+        self.record(elapsed);
+    }
+
+    // Complete a sink, an error is logged and counted as a failure of the stage
+    pub fn finish(self: &Arc<Self>, f: impl FnOnce() -> Result<()>) -> Result<()> {
+        let (result, elapsed) = self.enter(f);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if let Err(e) = &result {
+            log::error!("cannot finish {}: {}", self.name, e);
+            self.failures.fetch_add(1, Ordering::Relaxed);
         }
+        result
+    }
+
+    fn record(&self, elapsed: Duration) {
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.latency.record(elapsed);
+    }
+}
+
+pub struct StageIter<I> {
+    iter: I,
+    stage: Arc<StageStats>,
+    elapsed: Duration,
+}
+
+impl<T: 'static, I: Iterator<Item = FlowFile<T>>> Iterator for StageIter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.iter;
+        let (item, elapsed) = self.stage.enter(|| iter.next());
+        self.elapsed += elapsed;
+
+        if let Some(item) = &item {
+            self.stage.items_out.fetch_add(1, Ordering::Relaxed);
+            let bytes = byte_size(&item.data);
+            self.stage.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        item
+    }
+}
+
+impl<I> Drop for StageIter<I> {
+    fn drop(&mut self) {
+        self.stage.record(self.elapsed)
     }
 }
 
+struct StatsInner {
+    total: AtomicU64,
+    start: SystemTime,
+    stages: Mutex<Vec<Arc<StageStats>>>,
+}
+
 #[derive(Clone)]
 pub struct Stats {
-    total: Arc<AtomicU64>,
-    start: SystemTime,
+    inner: Arc<StatsInner>,
 }
 
 impl Stats {
     pub fn new() -> Self {
         let me = Self {
-            total: Arc::new(AtomicU64::new(0)),
-            start: SystemTime::now(),
+            inner: Arc::new(StatsInner {
+                total: AtomicU64::new(0),
+                start: SystemTime::now(),
+                stages: Mutex::new(vec![]),
+            }),
         };
-        let clone = me.clone();
+
+        // stop reporting when the stats are dropped
+        let weak = Arc::downgrade(&me.inner);
         std::thread::spawn(move || loop {
             std::thread::sleep(Duration::from_secs(1));
-            clone.report();
+            match weak.upgrade() {
+                Some(inner) => inner.report(),
+                None => return,
+            }
         });
 
         me
     }
 
     pub fn increment(&self) {
-        self.total.fetch_add(1, Ordering::Relaxed);
+        self.inner.total.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn total(&self) -> u64 {
-        self.total.load(Ordering::Relaxed)
+        self.inner.total.load(Ordering::Relaxed)
     }
 
     pub fn elapsed(&self) -> Duration {
+        self.inner.elapsed()
+    }
+
+    pub fn stage(&self, name: &str) -> Arc<StageStats> {
+        let stage = Arc::new(StageStats::new(name.to_string()));
+        self.inner.stages.lock().unwrap().push(stage.clone());
+        stage
+    }
+
+    pub fn stages(&self) -> Vec<Arc<StageStats>> {
+        self.inner.stages.lock().unwrap().clone()
+    }
+
+    // A reference that does not keep the stats alive, so the summary is still reported when
+    // the last `Stats` is dropped
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn downgrade(&self) -> WeakStats {
+        WeakStats(Arc::downgrade(&self.inner))
+    }
+}
+
+#[cfg(feature = "prometheus")]
+#[derive(Clone)]
+pub(crate) struct WeakStats(std::sync::Weak<StatsInner>);
+
+#[cfg(feature = "prometheus")]
+impl WeakStats {
+    pub(crate) fn upgrade(&self) -> Option<Stats> {
+        self.0.upgrade().map(|inner| Stats { inner })
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsInner {
+    fn elapsed(&self) -> Duration {
         SystemTime::now().duration_since(self.start).unwrap()
     }
 
     fn report(&self) {
-        let total = self.total();
+        let total = self.total.load(Ordering::Relaxed);
         let elapsed = self.elapsed();
         let millis = elapsed.as_millis() as u64;
-        let rate = if millis > 0 { total * 1000 / millis } else { 0 };
+        let rate = (total * 1000).checked_div(millis).unwrap_or(0);
         log::info!("Processed {:10} items at {:10} msgs/sec", total, rate);
     }
+
+    fn summary(&self) {
+        let stages = self.stages.lock().unwrap();
+        if stages.is_empty() {
+            return;
+        }
+
+        let mut table = format!(
+            "{:<24} {:>10} {:>10} {:>8} {:>12} {:>10} {:>10} {:>10}",
+            "stage", "in", "out", "failed", "bytes", "time", "p50", "p99"
+        );
+        for stage in stages.iter() {
+            table.push_str(&format!(
+                "\n{:<24} {:>10} {:>10} {:>8} {:>12} {:>10.3?} {:>10.0?} {:>10.0?}",
+                stage.name(),
+                stage.items_in(),
+                stage.items_out(),
+                stage.failures(),
+                stage.bytes(),
+                stage.time(),
+                stage.latency().quantile(0.5),
+                stage.latency().quantile(0.99),
+            ));
+        }
+        log::info!("Stage summary:\n{}", table);
+    }
 }
 
-impl Drop for Stats {
+impl Drop for StatsInner {
     fn drop(&mut self) {
-        self.report()
+        self.report();
+        self.summary();
     }
 }
 
-pub trait Transform: From<Vec<String>> {
+// Iterator for transformers whose iterator type cannot be named, e.g. because it holds closures
+pub type BoxIter<T> = Box<dyn Iterator<Item = FlowFile<T>> + Send>;
+
+pub trait Transform: TryFrom<Vec<String>> {
     type Input;
     type Output;
     type Iter: Iterator<Item = FlowFile<Self::Output>> + Send;
@@ -102,33 +666,34 @@ pub trait Transform: From<Vec<String>> {
     fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter;
 }
 
-pub trait StartTransform: From<Vec<String>> {
+pub trait StartTransform: TryFrom<Vec<String>> {
     type Output;
     type Iter: Iterator<Item = FlowFile<Self::Output>> + Send;
 
     fn start(self) -> Self::Iter;
 }
 
-pub trait CloseTransform: From<Vec<String>> {
+pub trait CloseTransform: TryFrom<Vec<String>> {
     type Input;
 
     fn close(&self, input: FlowFile<Self::Input>);
+
+    // Called once after the last item, to flush and complete the output
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub struct CloseableIter<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> {
     iter: I,
-    has_failed: &'static AtomicBool,
+    has_failed: Arc<Failed>,
     on_success: F1,
     on_failure: F2,
 }
 
 impl<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> CloseableIter<R, I, F1, F2> {
     pub fn new(iter: I, on_success: F1, on_failure: F2) -> Self {
-        let failed = AtomicBool::new(false);
-
-        // For performance reasons, put the bool on the stack and leak it (make it 'static').
-        // Then we don't need to refcount it all the way down the pipeline (via Arc).
-        let has_failed = Box::leak(Box::new(failed));
+        let has_failed = Failed::new();
 
         Self {
             iter,
@@ -137,6 +702,10 @@ impl<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> CloseableIter<R, I,
             on_failure,
         }
     }
+
+    pub fn mark_failed(&self) {
+        self.has_failed.set();
+    }
 }
 
 impl<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> Iterator
@@ -147,7 +716,7 @@ impl<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> Iterator
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next().map(|mut f| {
             if f.meta.failed.is_none() {
-                f.meta.failed = Some(self.has_failed);
+                f.meta.failed = Some(self.has_failed.clone());
             }
             f
         })
@@ -156,7 +725,7 @@ impl<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> Iterator
 
 impl<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> Drop for CloseableIter<R, I, F1, F2> {
     fn drop(&mut self) {
-        if self.has_failed.load(Ordering::SeqCst) {
+        if self.has_failed.get() {
             (self.on_failure)()
         } else {
             (self.on_success)()
@@ -164,8 +733,298 @@ impl<R, I: Iterator<Item = FlowFile<R>>, F1: Fn(), F2: Fn()> Drop for CloseableI
     }
 }
 
-pub trait Junction: From<Vec<String>> {
+pub trait Junction: TryFrom<Vec<String>> {
     type Input;
 
     fn split(&self, input: &FlowFile<Self::Input>) -> u8;
 }
+
+type Threads = Arc<Mutex<Vec<JoinHandle<()>>>>;
+
+// Queue capacity of the stages of a pipeline, when it does not say otherwise
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+// Threads of the transformers and sinks of a pipeline, when it does not say otherwise
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+// A stream of flow files behind a bounded queue. Every stage runs on its own threads and
+// blocks when the queue of the next stage is full, so a fast producer cannot outrun a slow sink.
+//
+// Completion callbacks of sources, e.g. `Unpack` moving a processed file, hang off the meta of
+// the items, so they run once the last item derived from a source has left the flow.
+pub struct Flow<T> {
+    rx: Receiver<FlowFile<T>>,
+    threads: Threads,
+    stats: Stats,
+}
+
+fn recv<T>(rx: &Mutex<Receiver<T>>) -> Option<T> {
+    rx.lock().unwrap().recv().ok()
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(threads: &Threads, f: F) {
+    let handle = std::thread::spawn(f);
+    threads.lock().unwrap().push(handle);
+}
+
+// Stages of a flow are named after their position and type, e.g. `2 Unpack`
+fn stage<X>(stats: &Stats) -> Arc<StageStats> {
+    let name = short_type_name(std::any::type_name::<X>());
+    let name = name.split('<').next().unwrap_or_default();
+    stats.stage(&format!("{} {}", stats.stages().len() + 1, name))
+}
+
+impl<T: Send + 'static> Flow<T> {
+    pub fn start<S>(start: S, capacity: usize, stats: &Stats) -> Self
+    where
+        S: StartTransform<Output = T> + Send + 'static,
+    {
+        let stage = stage::<S>(stats);
+        Self::start_with(stage, move || start.start(), None, capacity, stats)
+    }
+
+    // Like `start`, with failures going to `errors` instead of the log
+    pub fn start_with_errors<S>(
+        start: S,
+        errors: Arc<dyn ErrorSink>,
+        capacity: usize,
+        stats: &Stats,
+    ) -> Self
+    where
+        S: StartTransform<Output = T> + Send + 'static,
+    {
+        let stage = stage::<S>(stats);
+        Self::start_with(stage, move || start.start(), Some(errors), capacity, stats)
+    }
+
+    pub(crate) fn start_with<I>(
+        stage: Arc<StageStats>,
+        start: impl FnOnce() -> I + Send + 'static,
+        errors: Option<Arc<dyn ErrorSink>>,
+        capacity: usize,
+        stats: &Stats,
+    ) -> Self
+    where
+        I: Iterator<Item = FlowFile<T>>,
+    {
+        let (tx, rx) = sync_channel(capacity);
+        let handle = std::thread::spawn(move || {
+            for mut i in stage.transform(start) {
+                if let Some(errors) = &errors {
+                    i.meta.set_error_sink(errors.clone());
+                }
+                if tx.send(i).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            rx,
+            threads: Arc::new(Mutex::new(vec![handle])),
+            stats: stats.clone(),
+        }
+    }
+
+    pub fn transform<X>(self, transform: X, threads: usize, capacity: usize) -> Flow<X::Output>
+    where
+        X: Transform<Input = T> + Send + Sync + 'static,
+        X::Output: Send + 'static,
+    {
+        let stage = stage::<X>(&self.stats);
+        self.transform_with(stage, move |i| transform.transform(i), threads, capacity)
+    }
+
+    pub(crate) fn transform_with<U, I>(
+        self,
+        stage: Arc<StageStats>,
+        transform: impl Fn(FlowFile<T>) -> I + Send + Sync + 'static,
+        threads: usize,
+        capacity: usize,
+    ) -> Flow<U>
+    where
+        U: Send + 'static,
+        I: Iterator<Item = FlowFile<U>>,
+    {
+        let (tx, out) = sync_channel(capacity);
+        let rx = Arc::new(Mutex::new(self.rx));
+        let transform = Arc::new(transform);
+
+        let flow = Flow {
+            rx: out,
+            threads: self.threads,
+            stats: self.stats,
+        };
+
+        for _ in 0..threads.max(1) {
+            let (rx, tx, transform) = (rx.clone(), tx.clone(), transform.clone());
+            let stage = stage.clone();
+            spawn(&flow.threads, move || {
+                while let Some(i) = recv(&rx) {
+                    for o in stage.transform(|| transform(i)) {
+                        if tx.send(o).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+
+        flow
+    }
+
+    // Split the flow in `branches` flows, items for which the junction picks a branch that
+    // does not exist are failed
+    pub fn split<J>(self, junction: J, branches: usize, capacity: usize) -> Vec<Flow<T>>
+    where
+        J: Junction<Input = T> + Send + 'static,
+    {
+        let stage = stage::<J>(&self.stats);
+        let split = move |i: FlowFile<T>| (junction.split(&i), i);
+        self.split_with(stage, split, branches, capacity)
+    }
+
+    pub(crate) fn split_with(
+        self,
+        stage: Arc<StageStats>,
+        split: impl Fn(FlowFile<T>) -> (u8, FlowFile<T>) + Send + 'static,
+        branches: usize,
+        capacity: usize,
+    ) -> Vec<Flow<T>> {
+        let (txs, rxs): (Vec<_>, Vec<_>) = (0..branches).map(|_| sync_channel(capacity)).unzip();
+        let Self { rx, threads, stats } = self;
+
+        let flows: Vec<_> = rxs
+            .into_iter()
+            .map(|rx| Flow {
+                rx,
+                threads: threads.clone(),
+                stats: stats.clone(),
+            })
+            .collect();
+
+        spawn(&threads, move || {
+            for i in rx {
+                let (branch, i) = stage.split(|| split(i));
+                match txs.get(branch as usize) {
+                    Some(tx) => {
+                        // a branch that is closed early does not stop the other branches
+                        let _ = tx.send(i);
+                    }
+                    None => i.meta.fail(Error::NoBranch(branch)),
+                }
+            }
+        });
+
+        flows
+    }
+
+    // Merge flows into one. Joining the executor of the merged flow also waits for the stages
+    // of the merged flows that were started before.
+    pub fn merge(flows: Vec<Flow<T>>, capacity: usize) -> Result<Self> {
+        let (threads, stats) = match flows.first() {
+            Some(flow) => (flow.threads.clone(), flow.stats.clone()),
+            None => return Err(Error::Args("there are no flows to merge".into())),
+        };
+        for flow in &flows[1..] {
+            if !Arc::ptr_eq(&flow.threads, &threads) {
+                let handles: Vec<_> = flow.threads.lock().unwrap().drain(..).collect();
+                threads.lock().unwrap().extend(handles);
+            }
+        }
+
+        let (tx, rx) = sync_channel(capacity);
+        let merged = Self { rx, threads, stats };
+
+        for flow in flows {
+            let tx = tx.clone();
+            spawn(&merged.threads, move || {
+                for i in flow.rx {
+                    if tx.send(i).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        Ok(merged)
+    }
+
+    pub fn close<C>(self, close: C, threads: usize) -> Executor
+    where
+        C: CloseTransform<Input = T> + Send + Sync + 'static,
+    {
+        let stage = stage::<C>(&self.stats);
+        let close = Arc::new(close);
+        let finish = close.clone();
+        let close = move |i| close.close(i);
+        self.close_with(stage, close, move || finish.finish(), threads)
+    }
+
+    // The last thread to run out of items calls `finish`
+    pub(crate) fn close_with(
+        self,
+        stage: Arc<StageStats>,
+        close: impl Fn(FlowFile<T>) + Send + Sync + 'static,
+        finish: impl FnOnce() -> Result<()> + Send + 'static,
+        threads: usize,
+    ) -> Executor {
+        let rx = Arc::new(Mutex::new(self.rx));
+        let close = Arc::new(close);
+        let finish = Arc::new(Mutex::new(Some(finish)));
+
+        let executor = Executor {
+            threads: self.threads,
+            error: Arc::new(Mutex::new(None)),
+        };
+
+        let running = Arc::new(AtomicUsize::new(threads.max(1)));
+        for _ in 0..threads.max(1) {
+            let (rx, close, stats) = (rx.clone(), close.clone(), self.stats.clone());
+            let (stage, running, finish) = (stage.clone(), running.clone(), finish.clone());
+            let error = executor.error.clone();
+            spawn(&executor.threads, move || {
+                while let Some(i) = recv(&rx) {
+                    stage.close(i, |i| close(i));
+                    stats.increment();
+                }
+                if running.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    if let Some(finish) = finish.lock().unwrap().take() {
+                        *error.lock().unwrap() = stage.finish(finish).err();
+                    }
+                }
+            });
+        }
+
+        executor
+    }
+}
+
+pub struct Executor {
+    threads: Threads,
+    // of finishing the sink
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl Executor {
+    // Wait for all stages to finish, also those of the other branches of a split flow. Fails
+    // when the sink cannot be finished.
+    pub fn join(self) -> Result<()> {
+        loop {
+            let handle = match self.threads.lock().unwrap().pop() {
+                Some(handle) => handle,
+                None => break,
+            };
+            if let Err(e) = handle.join() {
+                std::panic::resume_unwind(e);
+            }
+        }
+
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,679 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::record::Record;
+use crate::transformers::{bytes, decode, key_value, line_meta, CsvRecords};
+
+use glob::glob;
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JoinKind {
+    // records with a match are joined, the others are emitted as they are. Records that are
+    // referred to are left out, they are part of the records referring to them.
+    Enrich,
+    // only records with a match
+    Inner,
+    // all records, with empty fields when there is no match
+    Left,
+    // only records without a match
+    Anti,
+}
+
+impl JoinKind {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "enrich" => Ok(Self::Enrich),
+            "inner" => Ok(Self::Inner),
+            "left" => Ok(Self::Left),
+            "anti" => Ok(Self::Anti),
+            _ => Err(Error::Args(format!(
+                "`kind` needs enrich, inner, left or anti, got `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+struct JoinSpec {
+    // the columns of a record that refer to another record
+    left: Vec<String>,
+    // the columns that are referred to, as many as `left`
+    right: Vec<String>,
+    // the columns of the referred record that are appended
+    columns: Vec<String>,
+    kind: JoinKind,
+}
+
+impl JoinSpec {
+    fn output(
+        &self,
+        input: FlowFile<csv::StringRecord>,
+        matched: Option<&Vec<String>>,
+    ) -> Option<FlowFile<csv::StringRecord>> {
+        let FlowFile { mut data, meta } = input;
+
+        match (self.kind, matched) {
+            (JoinKind::Anti, Some(_)) | (JoinKind::Inner, None) => return None,
+            (_, Some(values)) => values.iter().for_each(|v| data.push_field(v)),
+            (JoinKind::Left, None) => self.columns.iter().for_each(|_| data.push_field("")),
+            (JoinKind::Enrich, None) | (JoinKind::Anti, None) => (),
+        }
+
+        Some(FlowFile { data, meta })
+    }
+
+    // The key and the appended values of a record that can be referred to
+    fn entry(&self, record: &Record) -> Result<(String, Vec<String>)> {
+        let key = key(record, &self.right)?;
+        let values = self
+            .columns
+            .iter()
+            .map(|c| field(record, c).map(str::to_string))
+            .collect::<Result<_>>()?;
+        Ok((key, values))
+    }
+}
+
+fn field<'a>(record: &'a Record, column: &str) -> Result<&'a str> {
+    record
+        .get(column)
+        .ok_or_else(|| Error::UnknownColumn(column.to_string()))
+}
+
+// The key of a record in the given columns, empty when one of them is empty
+fn key(record: &Record, columns: &[String]) -> Result<String> {
+    let mut key = String::new();
+    for (i, column) in columns.iter().enumerate() {
+        let value = field(record, column)?;
+        if value.is_empty() {
+            return Ok(String::new());
+        }
+        if i > 0 {
+            // a separator that does not appear in text
+            key.push('\u{1f}');
+        }
+        key.push_str(value);
+    }
+    Ok(key)
+}
+
+fn fail(input: FlowFile<Record>, error: Error) {
+    let raw = input.data.values().iter().collect::<Vec<_>>().join(",");
+    input.meta.fail_with_data(error, raw);
+}
+
+// Joins CSV records with the records they refer to, appending columns of the referred record.
+//
+// Options are given as `key=value`, columns by name or number and comma separated:
+// left=ref, right=id (as many columns as `left`, for composite keys), columns=value,
+// kind=enrich (default, or `inner`, `left`, `anti`), lookup=glob, memory=256M, spill=dir, and the options
+// of `Csv`. `CsvInnerJoin id value ref` is short for `right=id columns=value left=ref`, and the
+// default is `CsvInnerJoin 0 1 2`.
+//
+// Without `lookup`, records refer to records of the same file, the last record of a key wins.
+// Records with an empty reference are not joined, the others are sorted by reference together
+// with the records they can refer to, and joined at the end of the file. With `lookup`, the
+// files matching the glob are read with the same `Csv` options and loaded once as the table that
+// every record is joined against, and records are emitted right away.
+//
+// The sorted records take up to `memory` bytes, beyond that they are spilled to sorted runs in
+// `spill` (the temp directory by default), which are merged at the end of the file.
+pub struct CsvInnerJoin {
+    records: CsvRecords,
+    spec: Arc<JoinSpec>,
+    lookup: Option<Arc<HashMap<String, Vec<String>>>>,
+    memory: usize,
+    spill: PathBuf,
+}
+
+impl Default for CsvInnerJoin {
+    fn default() -> Self {
+        Self::try_from(vec![]).unwrap()
+    }
+}
+
+impl TryFrom<Vec<String>> for CsvInnerJoin {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let (positional, args): (Vec<_>, Vec<_>) = args.into_iter().partition(|a| !a.contains('='));
+        let mut spec = match positional.as_slice() {
+            [] => JoinSpec {
+                left: vec!["2".into()],
+                right: vec!["0".into()],
+                columns: vec!["1".into()],
+                kind: JoinKind::Enrich,
+            },
+            [right, value, left] => JoinSpec {
+                left: vec![left.clone()],
+                right: vec![right.clone()],
+                columns: vec![value.clone()],
+                kind: JoinKind::Enrich,
+            },
+            _ => {
+                return Err(Error::Args(
+                    "CsvInnerJoin needs the id, value and reference columns".into(),
+                ))
+            }
+        };
+
+        let mut lookup = None;
+        let mut memory = 256 << 20;
+        let mut spill = std::env::temp_dir();
+        let mut csv_args = vec![];
+        for arg in args {
+            let (key, value) = key_value(&arg)?;
+            match key.as_str() {
+                "left" => spec.left = value.split(',').map(str::to_string).collect(),
+                "right" => spec.right = value.split(',').map(str::to_string).collect(),
+                "columns" => spec.columns = value.split(',').map(str::to_string).collect(),
+                "kind" => spec.kind = JoinKind::parse(&value)?,
+                "lookup" => lookup = Some(value),
+                "memory" => memory = bytes(&value)?,
+                "spill" => spill = PathBuf::from(value),
+                _ => csv_args.push(arg),
+            }
+        }
+        if spec.left.len() != spec.right.len() {
+            return Err(Error::Args(
+                "CsvInnerJoin needs as many `left` as `right` columns".into(),
+            ));
+        }
+        let records = CsvRecords::try_from(csv_args)?;
+
+        let lookup = match lookup {
+            Some(pattern) => Some(Arc::new(load(&pattern, &records, &spec)?)),
+            None => None,
+        };
+
+        Ok(Self {
+            records,
+            spec: Arc::new(spec),
+            lookup,
+            memory,
+            spill,
+        })
+    }
+}
+
+// Load the lookup table, the last record of a key wins
+fn load(
+    pattern: &str,
+    records: &CsvRecords,
+    spec: &JoinSpec,
+) -> Result<HashMap<String, Vec<String>>> {
+    let mut table = HashMap::new();
+    let mut found = false;
+
+    for path in glob(pattern)? {
+        let path = path.map_err(std::io::Error::from)?;
+        let (data, _codec) = decode(File::open(&path)?, &path)?;
+
+        let mut reader = records.csv().reader(data);
+        let headers = records.csv().headers(&mut reader)?;
+        for values in reader.records() {
+            let record = Record::new(headers.clone(), values?);
+            let (key, values) = spec.entry(&record)?;
+            if !key.is_empty() {
+                table.insert(key, values);
+            }
+        }
+        found = true;
+    }
+
+    if !found {
+        return Err(Error::Args(format!("no lookup files match `{}`", pattern)));
+    }
+
+    Ok(table)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Side {
+    // a record that can be referred to, with the columns to append, followed by the record
+    // itself when it waits to be emitted. Sorted first, so the records referring to its key
+    // find it.
+    Referred,
+    // a record with a reference, waiting for the end of the file
+    Referring,
+}
+
+// A record sorted by key until the end of the file
+struct Entry {
+    key: String,
+    side: Side,
+    line: u64,
+    values: csv::StringRecord,
+}
+
+impl Entry {
+    // Roughly the memory the entry takes
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.key.len() + self.values.as_slice().len()
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.key, self.side, self.line).cmp(&(&other.key, other.side, other.line))
+    }
+}
+
+type Entries = Box<dyn Iterator<Item = Result<Entry>> + Send>;
+
+// Runs merged at once, more runs are merged in passes
+const FAN_IN: usize = 16;
+
+// Names of spill files are unique within the process
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+// A file of entries sorted by key, removed when dropped
+struct Run {
+    path: PathBuf,
+}
+
+impl Run {
+    fn write(dir: &Path, entries: impl Iterator<Item = Result<Entry>>) -> Result<Self> {
+        let n = SPILLS.fetch_add(1, AtomicOrdering::Relaxed);
+        let path = dir.join(format!("join-{}-{}.csv", std::process::id(), n));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let run = Self { path };
+
+        // entries have as many fields as their record
+        let mut writer = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(BufWriter::new(file));
+        for entry in entries {
+            let entry = entry?;
+            let side = match entry.side {
+                Side::Referred => "0",
+                Side::Referring => "1",
+            };
+            let line = entry.line.to_string();
+            let fields = [entry.key.as_str(), side, line.as_str()];
+            writer.write_record(fields.iter().copied().chain(entry.values.iter()))?;
+        }
+        writer.flush()?;
+
+        Ok(run)
+    }
+
+    fn read(self) -> Result<Entries> {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(&self.path)?;
+
+        // the run is dropped, and removed, once it is read
+        Ok(Box::new(reader.into_records().map(move |record| {
+            let record = record?;
+            let side = match record.get(1) {
+                Some("0") => Some(Side::Referred),
+                Some("1") => Some(Side::Referring),
+                _ => None,
+            };
+            let line = record.get(2).and_then(|l| l.parse().ok());
+            match (record.get(0), side, line) {
+                (Some(key), Some(side), Some(line)) => Ok(Entry {
+                    key: key.to_string(),
+                    side,
+                    line,
+                    values: record.iter().skip(3).collect(),
+                }),
+                _ => {
+                    let msg = format!("corrupt spill file {}", self.path.display());
+                    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg).into())
+                }
+            }
+        })))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Entries kept in memory up to the budget and spilled to sorted runs beyond it
+struct Pending {
+    memory: Vec<Entry>,
+    size: usize,
+    budget: usize,
+    dir: PathBuf,
+    runs: Vec<Run>,
+}
+
+impl Pending {
+    fn push(&mut self, entry: Entry) -> Result<()> {
+        self.size += entry.size();
+        self.memory.push(entry);
+        if self.size <= self.budget {
+            return Ok(());
+        }
+
+        self.memory.sort_unstable();
+        match Run::write(&self.dir, self.memory.drain(..).map(Ok)) {
+            Ok(run) => {
+                self.runs.push(run);
+                self.size = 0;
+                Ok(())
+            }
+            Err(e) => {
+                // the entries are lost, keep the next ones in memory rather than losing them too
+                self.budget = usize::MAX;
+                Err(e)
+            }
+        }
+    }
+
+    // Merge the first `FAN_IN` runs into one
+    fn merge_runs(&mut self) -> Result<()> {
+        let sources = self.runs.drain(..FAN_IN).map(Run::read).collect();
+        let run = Run::write(&self.dir, Merge::new(sources))?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    // All entries, merged in key order
+    fn drain(&mut self) -> Merge {
+        let mut memory = std::mem::take(&mut self.memory);
+        memory.sort_unstable();
+        self.size = 0;
+
+        // the entries in memory take one of the runs that are merged at once
+        let mut sources: Vec<Result<Entries>> = vec![Ok(Box::new(memory.into_iter().map(Ok)))];
+        while self.runs.len() >= FAN_IN {
+            if let Err(e) = self.merge_runs() {
+                sources.push(Err(e));
+                break;
+            }
+        }
+        sources.extend(self.runs.drain(..).map(Run::read));
+
+        Merge::new(sources)
+    }
+}
+
+struct Merge {
+    sources: Vec<Entries>,
+    heads: BinaryHeap<Reverse<(Entry, usize)>>,
+    errors: Vec<Error>,
+}
+
+impl Merge {
+    fn new(sources: Vec<Result<Entries>>) -> Self {
+        let mut merge = Self {
+            sources: vec![],
+            heads: BinaryHeap::new(),
+            errors: vec![],
+        };
+        for source in sources {
+            match source {
+                Ok(source) => merge.sources.push(source),
+                Err(e) => merge.errors.push(e),
+            }
+        }
+        for i in 0..merge.sources.len() {
+            merge.advance(i);
+        }
+        merge
+    }
+
+    // Take the next entry of a source, a source that fails is not read any further
+    fn advance(&mut self, i: usize) {
+        match self.sources[i].next() {
+            Some(Ok(entry)) => self.heads.push(Reverse((entry, i))),
+            Some(Err(e)) => self.errors.push(e),
+            None => (),
+        }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.errors.pop() {
+            return Some(Err(e));
+        }
+        let Reverse((entry, i)) = self.heads.pop()?;
+        self.advance(i);
+        Some(Ok(entry))
+    }
+}
+
+// Joins the sorted entries: every key has its referred records first, then the records
+// referring to it
+struct Flush {
+    merge: Merge,
+    key: Option<String>,
+    // the columns of the last referred record of the key
+    matched: Option<Vec<String>>,
+    // the records of the key waiting to be emitted, unless other records refer to them
+    waiting: Vec<FlowFile<csv::StringRecord>>,
+    referred: bool,
+    ready: Vec<FlowFile<csv::StringRecord>>,
+}
+
+pub struct CsvJoinIter<I> {
+    records: I,
+    spec: Arc<JoinSpec>,
+    // the meta of the file, to restore the meta of the pending records
+    meta: FlowFileMeta,
+    pending: Pending,
+    flush: Option<Flush>,
+}
+
+impl<I> CsvJoinIter<I> {
+    fn push(&mut self, entry: Entry) {
+        if let Err(e) = self.pending.push(entry) {
+            self.meta.fail(e);
+        }
+    }
+
+    // The next joined record of the end of the file
+    fn flush(&mut self) -> Option<FlowFile<csv::StringRecord>> {
+        let flush = self.flush.as_mut()?;
+        loop {
+            if let Some(r) = flush.ready.pop() {
+                return Some(r);
+            }
+            let entry = match flush.merge.next() {
+                Some(Ok(entry)) => Some(entry),
+                Some(Err(e)) => {
+                    self.meta.fail(e);
+                    continue;
+                }
+                None => None,
+            };
+            if flush.key.as_ref() != entry.as_ref().map(|e| &e.key) {
+                if !flush.referred {
+                    flush.ready.append(&mut flush.waiting);
+                }
+                flush.waiting.clear();
+                flush.key = entry.as_ref().map(|e| e.key.clone());
+                flush.matched = None;
+                flush.referred = false;
+            }
+            let entry = match entry {
+                Some(entry) => entry,
+                None if flush.ready.is_empty() => return None,
+                None => continue,
+            };
+
+            match entry.side {
+                Side::Referred => {
+                    let n = self.spec.columns.len();
+                    flush.matched = Some(entry.values.iter().take(n).map(String::from).collect());
+                    if entry.values.len() > n {
+                        flush.waiting.push(FlowFile {
+                            data: entry.values.iter().skip(n).collect(),
+                            meta: line_meta(&self.meta, entry.line),
+                        });
+                    }
+                }
+                Side::Referring => {
+                    flush.referred = true;
+                    flush.waiting.clear();
+                    let r = FlowFile {
+                        data: entry.values,
+                        meta: line_meta(&self.meta, entry.line),
+                    };
+                    if let Some(r) = self.spec.output(r, flush.matched.as_ref()) {
+                        return Some(r);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = FlowFile<Record>>> Iterator for CsvJoinIter<I> {
+    type Item = FlowFile<csv::StringRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.flush.is_some() {
+                return self.flush();
+            }
+
+            let r = match self.records.next() {
+                Some(r) => r,
+                None => {
+                    self.flush = Some(Flush {
+                        merge: self.pending.drain(),
+                        key: None,
+                        matched: None,
+                        waiting: vec![],
+                        referred: false,
+                        ready: vec![],
+                    });
+                    continue;
+                }
+            };
+
+            let keys = key(&r.data, &self.spec.left)
+                .and_then(|reference| Ok((reference, self.spec.entry(&r.data)?)));
+            let (reference, (key, mut values)) = match keys {
+                Ok(keys) => keys,
+                Err(e) => {
+                    fail(r, e);
+                    continue;
+                }
+            };
+            let line = match r.meta.attribute(LINE) {
+                Some(Attribute::UInt(line)) => *line,
+                _ => 0,
+            };
+            let FlowFile { data, meta } = r;
+            let data = data.into_values();
+
+            // enriching leaves out the record when others refer to it, which is only known at
+            // the end of the file
+            let waits =
+                reference.is_empty() && !key.is_empty() && self.spec.kind == JoinKind::Enrich;
+            if waits {
+                values.extend(data.iter().map(String::from));
+            }
+            if !key.is_empty() {
+                self.push(Entry {
+                    key,
+                    side: Side::Referred,
+                    line,
+                    values: values.into(),
+                });
+            }
+
+            if waits {
+                continue;
+            }
+            if reference.is_empty() {
+                match self.spec.output(FlowFile { data, meta }, None) {
+                    Some(r) => return Some(r),
+                    None => continue,
+                }
+            }
+            self.push(Entry {
+                key: reference,
+                side: Side::Referring,
+                line,
+                values: data,
+            });
+        }
+    }
+}
+
+impl Transform for CsvInnerJoin {
+    type Input = Box<dyn Read + Send + Sync>;
+    type Output = csv::StringRecord;
+    type Iter = BoxIter<Self::Output>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let meta = input.meta.clone();
+        let records = self.records.transform(input);
+        let spec = self.spec.clone();
+
+        match &self.lookup {
+            Some(table) => {
+                let table = table.clone();
+                Box::new(records.filter_map(move |r| {
+                    let matched = match key(&r.data, &spec.left) {
+                        Ok(reference) if reference.is_empty() => None,
+                        Ok(reference) => table.get(&reference),
+                        Err(e) => {
+                            fail(r, e);
+                            return None;
+                        }
+                    };
+                    let FlowFile { data, meta } = r;
+                    let r = FlowFile {
+                        data: data.into_values(),
+                        meta,
+                    };
+                    spec.output(r, matched)
+                }))
+            }
+            None => Box::new(CsvJoinIter {
+                records,
+                spec,
+                meta,
+                pending: Pending {
+                    memory: vec![],
+                    size: 0,
+                    budget: self.memory,
+                    dir: self.spill.clone(),
+                    runs: vec![],
+                },
+                flush: None,
+            }),
+        }
+    }
+}
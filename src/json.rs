@@ -0,0 +1,147 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::record::{Field, Record};
+use crate::transformers::{CheckContains, Lines};
+
+use serde_json::{Map, Value};
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::marker::PhantomData;
+
+// Parses a file with one JSON value per line, blank lines are skipped. Takes the options of
+// `Lines`, i.e. `checkpoint=path`.
+#[derive(Default)]
+pub struct JsonLines {
+    lines: Lines,
+}
+
+impl TryFrom<Vec<String>> for JsonLines {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        Ok(Self {
+            lines: Lines::try_from(args)?,
+        })
+    }
+}
+
+impl Transform for JsonLines {
+    type Input = Box<dyn Read + Send + Sync>;
+    type Output = Value;
+    type Iter = BoxIter<Self::Output>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let values = self.lines.transform(input).filter_map(|line| {
+            let FlowFile { data, meta } = line;
+            if data.trim().is_empty() {
+                return None;
+            }
+            match serde_json::from_str(&data) {
+                Ok(data) => Some(FlowFile { data, meta }),
+                Err(e) => {
+                    meta.fail_with_data(Error::Json(e), data);
+                    None
+                }
+            }
+        });
+
+        Box::new(values)
+    }
+}
+
+// Items that can be serialized by `ToJson`
+pub trait IntoJson {
+    fn into_json(self) -> Value;
+}
+
+impl IntoJson for Value {
+    fn into_json(self) -> Value {
+        self
+    }
+}
+
+// An array of the fields
+impl IntoJson for csv::StringRecord {
+    fn into_json(self) -> Value {
+        self.iter().map(|v| Value::String(v.to_string())).collect()
+    }
+}
+
+// An object with a key per column
+impl IntoJson for Record {
+    fn into_json(self) -> Value {
+        let mut object = Map::new();
+        for (i, value) in self.values().iter().enumerate() {
+            let key = match self.headers().get(i) {
+                Some(name) => name.to_string(),
+                None => i.to_string(),
+            };
+            object.insert(key, Value::String(value.to_string()));
+        }
+        Value::Object(object)
+    }
+}
+
+// Serializes an item to a single line of JSON
+pub struct ToJson<A> {
+    marker: PhantomData<A>,
+}
+
+impl<A> Default for ToJson<A> {
+    fn default() -> Self {
+        Self {
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<A> From<Vec<String>> for ToJson<A> {
+    fn from(_args: Vec<String>) -> Self {
+        Self::default()
+    }
+}
+
+impl<A: IntoJson + Send + Sync + 'static> Transform for ToJson<A> {
+    type Input = A;
+    type Output = String;
+    type Iter = std::iter::Once<FlowFile<String>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { data, meta } = input;
+        let data = data.into_json().to_string();
+        std::iter::once(FlowFile { data, meta })
+    }
+}
+
+// Strings, numbers and booleans, at any depth, are searched. Keys are not.
+impl CheckContains for Value {
+    fn contains(&self, needle: &str) -> bool {
+        match self {
+            Value::Null => false,
+            Value::String(s) => s.contains(needle),
+            Value::Array(values) => values.iter().any(|v| v.contains(needle)),
+            Value::Object(object) => object.values().any(|v| v.contains(needle)),
+            scalar => scalar.to_string().contains(needle),
+        }
+    }
+}
+
+// A field is a key of an object, or a JSON pointer like `/user/name`. Strings are given without
+// quotes, other values as JSON.
+impl Field for Value {
+    fn field(&self, name: &str) -> Option<Cow<'_, str>> {
+        let value = if name.starts_with('/') {
+            self.pointer(name)?
+        } else {
+            self.get(name)?
+        };
+
+        match value {
+            Value::Null => None,
+            Value::String(s) => Some(Cow::Borrowed(s)),
+            value => Some(Cow::Owned(value.to_string())),
+        }
+    }
+}
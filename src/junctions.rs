@@ -1,5 +1,8 @@
+use crate::error::{Error, Result};
 use crate::framework::*;
+use crate::record::Field;
 
+use std::convert::TryFrom;
 use std::marker::PhantomData;
 
 pub struct SplitByExt<A> {
@@ -20,7 +23,7 @@ impl<A> Junction for SplitByExt<A> {
     type Input = A;
 
     fn split(&self, input: &FlowFile<Self::Input>) -> u8 {
-        let ext = input.meta.source().split('.').last().unwrap();
+        let ext = input.meta.source().split('.').next_back().unwrap();
 
         let pos = match self.exts.iter().position(|v| v == ext) {
             None => return 255,
@@ -30,3 +33,83 @@ impl<A> Junction for SplitByExt<A> {
         pos as u8
     }
 }
+
+// Split on the value of attribute `key`, the remaining arguments are the values of the branches
+pub struct SplitByAttribute<A> {
+    key: String,
+    values: Vec<String>,
+    _marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for SplitByAttribute<A> {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        if args.is_empty() {
+            return Err(Error::Args(
+                "SplitByAttribute needs an attribute key".into(),
+            ));
+        }
+
+        Ok(Self {
+            key: args.remove(0),
+            values: args,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<A> Junction for SplitByAttribute<A> {
+    type Input = A;
+
+    fn split(&self, input: &FlowFile<Self::Input>) -> u8 {
+        let value = match input.meta.attribute(&self.key) {
+            None => return 255,
+            Some(value) => value.to_string(),
+        };
+
+        match self.values.iter().position(|v| *v == value) {
+            None => 255,
+            Some(pos) => pos as u8,
+        }
+    }
+}
+
+// Split on the value of a field, the remaining arguments are the values of the branches
+pub struct SplitByField<A> {
+    field: String,
+    values: Vec<String>,
+    _marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for SplitByField<A> {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        if args.is_empty() {
+            return Err(Error::Args("SplitByField needs a field name".into()));
+        }
+
+        Ok(Self {
+            field: args.remove(0),
+            values: args,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<A: Field> Junction for SplitByField<A> {
+    type Input = A;
+
+    fn split(&self, input: &FlowFile<Self::Input>) -> u8 {
+        let value = match input.data.field(&self.field) {
+            None => return 255,
+            Some(value) => value,
+        };
+
+        match self.values.iter().position(|v| *v == value) {
+            None => 255,
+            Some(pos) => pos as u8,
+        }
+    }
+}
@@ -1,25 +1,54 @@
-#![feature(min_type_alias_impl_trait)]
-
+pub mod archive;
+pub mod checkpoint;
+pub mod codegen;
+#[cfg(feature = "parquet")]
+pub mod columnar;
+pub mod error;
 pub mod framework;
+pub mod join;
+#[cfg(feature = "json")]
+pub mod json;
 pub mod junctions;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+pub mod partition;
+pub mod pipeline;
+pub mod record;
+pub mod registry;
+pub mod rolling;
+pub mod text;
 pub mod transformers;
+pub mod watch;
 
 #[cfg(test)]
 mod tests {
+    use crate::archive::*;
+    #[cfg(feature = "parquet")]
+    use crate::columnar::*;
     use crate::framework::*;
+    use crate::join::*;
     use crate::junctions::*;
+    use crate::partition::*;
+    use crate::pipeline::*;
+    use crate::record::*;
+    use crate::registry::*;
+    use crate::text::*;
     use crate::transformers::*;
+    use crate::watch::*;
 
     use rayon::iter::ParallelBridge;
     use rayon::prelude::ParallelIterator;
 
-    #[test]
-    fn test_nonlinear_flow() {
-        let g = Glob::from(vec!["*.toml".to_string(), "a.csv".to_string()]);
-        let u = Unpack {};
+    use std::convert::TryFrom;
+
+    // Count the items of the files matching `patterns`, with toml files split into lines and
+    // csv files into records
+    fn nonlinear_flow(patterns: &[&str]) -> u64 {
+        let g = Glob::try_from(patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap();
+        let u = Unpack::default();
         let s = SplitByExt::from(vec!["toml".to_string(), "csv".to_string()]);
-        let l = Lines {};
-        let c = Csv {};
+        let l = Lines::default();
+        let c = Csv::default();
         let t = ToString::default();
         let n = Nullify::from(vec![]);
 
@@ -44,7 +73,1087 @@ mod tests {
                 _ => unreachable!(),
             });
 
-        let count = stats.total();
-        assert_eq!(count, 18);
+        stats.total()
+    }
+
+    #[test]
+    fn test_nonlinear_flow() {
+        // a.csv does not exist, every item is a line of a toml file
+        let toml = std::fs::read_to_string("Cargo.toml").unwrap();
+        assert_eq!(
+            nonlinear_flow(&["*.toml", "a.csv"]),
+            toml.lines().count() as u64
+        );
+
+        // the records pass the boxed iterator of `Csv`
+        assert_eq!(nonlinear_flow(&["testcase.csv"]), 4);
+    }
+
+    #[test]
+    fn test_runtime_pipeline() {
+        let src = r#"
+Glob testcase.csv
+Unpack
+Junction:2 SplitByExt csv
+Csv @threads=2 @capacity=1
+ToString
+Lines
+Nullify @threads=3
+"#;
+        let registry = Registry::default();
+        let pipeline = Pipeline::parse(src, &registry).unwrap();
+
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+
+        assert_eq!(stats.total(), 4);
+
+        for (src, msg) in [
+            ("Glob a.csv @threads=2\nNullify", "runs on a single thread"),
+            ("Glob a.csv\nNullify @threads=0", "positive number"),
+            ("Glob a.csv\nNullify @queue=8", "unknown option"),
+        ] {
+            let err = Pipeline::parse(src, &registry).err().unwrap();
+            assert!(err.message.contains(msg), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_runtime_pipeline_type_mismatch() {
+        let src = "Glob testcase.csv\nUnpack\nCsv\nWrite out.tar.gz\n";
+        let registry = Registry::default();
+        let err = Pipeline::parse(src, &registry).err().unwrap();
+
+        assert_eq!(err.line, 4);
+        assert!(err.message.contains("expects String"));
+        assert!(err.message.contains("`ToString`"));
+    }
+
+    #[test]
+    fn test_generate() {
+        // the example is built with the tests, so this also checks the generated code compiles
+        let src = include_str!("../examples/generated.pipeline");
+        let code = crate::codegen::generate(src, &Registry::default()).unwrap();
+        assert_eq!(code, include_str!("../examples/generated.rs"));
+    }
+
+    #[test]
+    fn test_graph_pipeline() {
+        let src = r#"
+glob: Glob testcase.csv Cargo.toml
+unpack: Unpack
+split: SplitByExt csv toml
+csv: Csv
+str: ToString
+lines: Lines
+sink: Nullify
+
+glob -> unpack -> split
+split -> csv -> str -> sink
+split -> lines -> sink
+"#;
+        let registry = Registry::default();
+        let pipeline = Pipeline::parse(src, &registry).unwrap();
+
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+
+        let toml = std::fs::read_to_string("Cargo.toml").unwrap();
+        assert_eq!(stats.total(), 4 + toml.lines().count() as u64);
+
+        let cycle = src.replace("lines -> sink", "lines -> split");
+        let err = Pipeline::parse(&cycle, &registry).err().unwrap();
+        assert_eq!(err.line, 4);
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let dir = std::env::temp_dir().join(format!("dead-letter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("bad.csv");
+        let output = dir.join("failed.jsonl");
+        std::fs::write(&input, "id,value\n1,hi\n2\n3,world\n").unwrap();
+
+        let src = format!(
+            "Glob {}\nUnpack\nCsv\nToString\nNullify\nOnError DeadLetter {}\n",
+            input.display(),
+            output.display()
+        );
+        let registry = Registry::default();
+        let pipeline = Pipeline::parse(&src, &registry).unwrap();
+
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+        assert_eq!(stats.total(), 2);
+
+        let failed = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(failed.lines().count(), 1);
+        assert!(failed.contains("bad.csv:1\""));
+        assert!(failed.contains("\"data\":\"2\""));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        // compacting writes the name with `.tmp` appended, not replacing the extension
+        let state = dir.join("state.tmp");
+        std::fs::write(&a, "1\n2\n3\n").unwrap();
+
+        let src = format!(
+            "Glob {}/*.txt\nUnpack checkpoint={}\nLines checkpoint={}\nNullify",
+            dir.display(),
+            state.display(),
+            state.display()
+        );
+        let run = || {
+            let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+            let stats = Stats::new();
+            pipeline.run(&stats).unwrap();
+            stats.total()
+        };
+
+        assert_eq!(run(), 3);
+
+        // completed files are skipped
+        std::fs::write(&b, "4\n5\n6\n7\n").unwrap();
+        assert_eq!(run(), 4);
+        assert_eq!(run(), 0);
+
+        // a file that was interrupted resumes after the last processed line
+        let c = dir.join("c.txt");
+        std::fs::write(&c, "8\n9\n10\n").unwrap();
+        let mut log = std::fs::read_to_string(&state).unwrap();
+        log.push_str(&format!("line 2 {}\n", c.display()));
+        std::fs::write(&state, log).unwrap();
+        assert_eq!(run(), 1);
+
+        // a failed line is not recorded as processed, a rerun retries it
+        let d = dir.join("d.txt");
+        std::fs::write(&d, b"11\n\xff\n12\n").unwrap();
+        assert_eq!(run(), 2);
+        let log = std::fs::read_to_string(&state).unwrap();
+        assert!(log.ends_with(&format!("line 1 {}\n", d.display())));
+        assert_eq!(run(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_actions() {
+        let dir = std::env::temp_dir().join(format!("file-actions-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good.txt"), "hello\n").unwrap();
+        std::fs::write(dir.join("bad.txt"), b"hello\n\xff\n").unwrap();
+
+        let src = format!(
+            "Glob {}/*.txt\nUnpack on_success=move:done on_failure=rename:.failed\nLines\nNullify",
+            dir.display()
+        );
+        let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+        pipeline.run(&Stats::new()).unwrap();
+
+        assert!(dir.join("done/good.txt").exists());
+        assert!(!dir.join("good.txt").exists());
+        assert!(dir.join("bad.txt.failed").exists());
+        assert!(!dir.join("bad.txt").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watch() {
+        use std::io::Write;
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.csv"), "a\n").unwrap();
+
+        let args = vec![
+            format!("{}/*.csv", dir.display()),
+            "settle=200".into(),
+            "poll=20".into(),
+        ];
+        let watch = Watch::try_from(args).unwrap();
+        let stop = watch.stop_handle();
+        let handle = std::thread::spawn(move || {
+            let paths = watch
+                .start()
+                .map(|f| f.data.file_name().unwrap().to_owned());
+            paths.collect::<Vec<_>>()
+        });
+
+        // a file that is still being written is only emitted once it settled
+        let mut file = std::fs::File::create(dir.join("b.csv")).unwrap();
+        for _ in 0..10 {
+            writeln!(file, "b").unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::fs::write(dir.join("c.txt"), "c\n").unwrap();
+        std::thread::sleep(Duration::from_millis(600));
+
+        stop.stop();
+        let paths = handle.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(paths, vec!["a.csv", "b.csv"]);
+
+        // files moved to where the patterns match them again would be processed again
+        let pipeline = |watch: &str, action: &str| {
+            let src = format!(
+                "Watch {}\nUnpack on_success={}\nLines\nNullify",
+                watch, action
+            );
+            Pipeline::parse(&src, &Registry::default()).map(|_| ())
+        };
+        let err = pipeline("in/**/*.csv", "move:done").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("moves files to `done`"));
+        assert!(pipeline("/in/*.csv", "move:/in").is_err());
+        assert!(pipeline("in/*.csv", "move:done").is_ok());
+        assert!(pipeline("in/**/*.csv", "move:../done").is_ok());
+        assert!(pipeline("/in/**/*.csv", "move:/done").is_ok());
+        assert!(pipeline("in/**/*.csv", "rename:.done").is_ok());
+    }
+
+    #[cfg(feature = "inotify")]
+    #[test]
+    fn test_watch_subdirectories() {
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("watch-tree-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // polling is too slow to notice the file, only events in the new subdirectory do
+        let args = vec![
+            format!("{}/**/*.csv", dir.display()),
+            "settle=0".into(),
+            "poll=60000".into(),
+        ];
+        let watch = Watch::try_from(args).unwrap();
+        let stop = watch.stop_handle();
+        let handle = std::thread::spawn(move || watch.start().next().map(|f| f.data));
+
+        std::thread::sleep(Duration::from_millis(100));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        std::fs::write(dir.join("sub/a.csv"), "a\n").unwrap();
+
+        let path = handle.join().unwrap();
+        stop.stop();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(path, Some(dir.join("sub/a.csv")));
+    }
+
+    #[test]
+    fn test_decompression() {
+        use std::io::{Read, Write};
+
+        let text = "hello\nworld\n";
+        let mut files: Vec<(&str, Vec<u8>)> = vec![("plain", text.into())];
+
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(text.as_bytes()).unwrap();
+        files.push(("gzip", gz.finish().unwrap()));
+
+        #[cfg(feature = "zstd")]
+        files.push(("zstd", zstd::encode_all(text.as_bytes(), 0).unwrap()));
+        #[cfg(feature = "bzip2")]
+        {
+            let mut bz = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+            bz.write_all(text.as_bytes()).unwrap();
+            files.push(("bzip2", bz.finish().unwrap()));
+        }
+        #[cfg(feature = "xz")]
+        {
+            let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+            xz.write_all(text.as_bytes()).unwrap();
+            files.push(("xz", xz.finish().unwrap()));
+        }
+        #[cfg(feature = "lz4")]
+        {
+            let mut lz = lz4::EncoderBuilder::new().build(vec![]).unwrap();
+            lz.write_all(text.as_bytes()).unwrap();
+            files.push(("lz4", lz.finish().0));
+        }
+
+        let dir = std::env::temp_dir().join(format!("decompression-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (codec, bytes) in files {
+            // no extension, the codec is detected by the magic bytes
+            let path = dir.join(codec);
+            std::fs::write(&path, bytes).unwrap();
+
+            let mut unpacked = Unpack::default().transform(FlowFile::new(path));
+            let mut file = unpacked.next().unwrap();
+            let mut data = String::new();
+            file.data.read_to_string(&mut data).unwrap();
+
+            assert_eq!(data, text, "{}", codec);
+            let attribute = file.meta.attribute(CODEC).map(|c| c.to_string());
+            assert_eq!(attribute.as_deref(), Some(codec).filter(|&c| c != "plain"));
+        }
+
+        // text that happens to start with the bzip2 magic is not decoded
+        let path = dir.join("bzh.csv");
+        std::fs::write(&path, "BZh,name\n1,a\n").unwrap();
+        let mut file = Unpack::default()
+            .transform(FlowFile::new(path))
+            .next()
+            .unwrap();
+        let mut data = String::new();
+        file.data.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "BZh,name\n1,a\n");
+        assert!(file.meta.attribute(CODEC).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_untar() {
+        use std::path::PathBuf;
+
+        let dir = std::env::temp_dir().join(format!("untar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.tar.gz");
+
+        let src = format!("Glob testcase.csv\nUnpack\nLines\nWrite {}", out.display());
+        let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+        pipeline.run(&Stats::new()).unwrap();
+
+        // the output of `Write` can be read back, one member per line
+        let src = format!("Glob {}\nUntar\nLines\nNullify", out.display());
+        let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+
+        let lines = std::fs::read_to_string("testcase.csv")
+            .unwrap()
+            .lines()
+            .count();
+        assert_eq!(stats.total(), lines as u64);
+
+        let untar = Untar::<PathBuf>::from(vec![]);
+        let mut sources: Vec<_> = untar
+            .transform(FlowFile::new(out.clone()))
+            .map(|f| f.meta.source().to_string())
+            .collect();
+        sources.sort();
+        assert_eq!(sources[0], format!("{}!testcase.csv:0", out.display()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn test_unzip() {
+        use std::io::{Read, Write};
+        use std::path::PathBuf;
+
+        let dir = std::env::temp_dir().join(format!("unzip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("in.zip");
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.add_directory("dir/", options).unwrap();
+        zip.start_file("dir/a.csv", options).unwrap();
+        zip.write_all(b"a,b\n1,2\n").unwrap();
+        zip.start_file("b.txt", options).unwrap();
+        zip.write_all(b"hello\n").unwrap();
+        zip.finish().unwrap();
+
+        let unzip = Unzip::<PathBuf>::from(vec![]);
+        let members: Vec<_> = unzip
+            .transform(FlowFile::new(path.clone()))
+            .map(|mut f| {
+                let mut data = String::new();
+                f.data.read_to_string(&mut data).unwrap();
+                (f.meta.source().to_string(), data)
+            })
+            .collect();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].0, format!("{}!dir/a.csv", path.display()));
+        assert_eq!(members[0].1, "a,b\n1,2\n");
+        assert_eq!(members[1].1, "hello\n");
+    }
+
+    #[test]
+    fn test_csv_options() {
+        use std::io::{Cursor, Read};
+
+        let records = |args: &[&str], text: &str| {
+            let csv = Csv::try_from(args.iter().map(|a| a.to_string()).collect::<Vec<_>>());
+            let reader = Box::new(Cursor::new(text.to_string())) as Box<dyn Read + Send + Sync>;
+            csv.unwrap()
+                .transform(FlowFile::new(reader))
+                .map(|r| r.data.iter().map(str::to_string).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let tsv = "# exported\n a \t'b c'\n1\t2\n";
+        let args = [
+            "delimiter=tab",
+            "quote='",
+            "headers=false",
+            "comment=#",
+            "trim=all",
+        ];
+        assert_eq!(records(&args, tsv), vec![vec!["a", "b c"], vec!["1", "2"]]);
+
+        let ragged = "a;b\n1;2;3\n4\n";
+        let args = ["delimiter=;", "flexible=true"];
+        assert_eq!(records(&args, ragged), vec![vec!["1", "2", "3"], vec!["4"]]);
+
+        // strict by default, the bad records go to the error sink
+        assert_eq!(records(&["delimiter=;"], ragged).len(), 0);
+
+        assert!(Csv::try_from(vec!["delimiter=ab".to_string()]).is_err());
+        assert!(Csv::try_from(vec!["separator=;".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        use std::io::{Cursor, Read};
+
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let reader =
+            Box::new(std::fs::File::open("testcase.csv").unwrap()) as Box<dyn Read + Send + Sync>;
+        let filter = FieldEquals::<Record>::try_from(args(&["ref", "3"])).unwrap();
+        let select = Select::try_from(args(&["value", "id"])).unwrap();
+        let rename = Rename::try_from(args(&["value=greeting"])).unwrap();
+
+        let records: Vec<_> = CsvRecords::default()
+            .transform(FlowFile::new(reader))
+            .flat_map(|r| filter.transform(r))
+            .flat_map(|r| select.transform(r))
+            .flat_map(|r| rename.transform(r))
+            .map(|r| r.data)
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].get("greeting"), Some("hello"));
+        assert_eq!(records[0].get("id"), Some("2"));
+        assert_eq!(records[0].get("ref"), None);
+        assert_eq!(
+            records[0].values(),
+            &csv::StringRecord::from(vec!["hello", "2"])
+        );
+
+        // without a header row, columns are addressed by number
+        let reader = Box::new(Cursor::new("a,b\n")) as Box<dyn Read + Send + Sync>;
+        let csv = CsvRecords::try_from(args(&["headers=false"])).unwrap();
+        let record = csv.transform(FlowFile::new(reader)).next().unwrap().data;
+        assert_eq!(record.get("1"), Some("b"));
+
+        let src = r#"
+Glob testcase.csv
+Unpack
+CsvRecords
+FieldEquals ref 3
+Select value
+Values
+ToString
+Nullify
+"#;
+        let pipeline = Pipeline::parse(src, &Registry::default()).unwrap();
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+        assert_eq!(stats.total(), 1);
+
+        let src = "Glob testcase.csv\nUnpack\nCsvRecords\nSelect missing\nNullify\n";
+        let pipeline = Pipeline::parse(src, &Registry::default()).unwrap();
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+        assert_eq!(stats.total(), 0);
+    }
+
+    #[test]
+    fn test_join() {
+        let join_file = |path: &std::path::Path, args: &[&str]| {
+            let join =
+                CsvInnerJoin::try_from(args.iter().map(|a| a.to_string()).collect::<Vec<_>>());
+            let reader = std::fs::File::open(path).unwrap();
+            let reader = Box::new(reader) as Box<dyn std::io::Read + Send + Sync>;
+            let mut records = join
+                .unwrap()
+                .transform(FlowFile::new(reader))
+                .map(|r| r.data.iter().collect::<Vec<_>>().join(","))
+                .collect::<Vec<_>>();
+            records.sort();
+            records
+        };
+        let join = |args: &[&str]| join_file("testcase.csv".as_ref(), args);
+
+        // records without a match are kept by default, and referred records are left out
+        let enrich = vec!["1,hi,", "2,hello,3,world", "4,test,5"];
+        assert_eq!(join(&[]), enrich);
+        assert_eq!(join(&["kind=enrich"]), enrich);
+        assert_eq!(join(&["id", "value", "ref"]), enrich);
+        assert_eq!(
+            join(&["left=ref", "right=id", "columns=id,value", "kind=inner"]),
+            vec!["2,hello,3,3,world"]
+        );
+        assert_eq!(
+            join(&["kind=left"]),
+            vec!["1,hi,,", "2,hello,3,world", "3,world,,", "4,test,5,"]
+        );
+        assert_eq!(join(&["kind=anti"]), vec!["1,hi,", "3,world,", "4,test,5"]);
+
+        // composite keys
+        let path = std::env::temp_dir().join(format!("join-keys-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "a,b,name,ra,rb\n1,x,one,,\n1,y,two,,\n2,x,three,1,y\n3,x,four,1,z\n",
+        )
+        .unwrap();
+        let records = join_file(&path, &["left=ra,rb", "right=a,b", "columns=name"]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            records,
+            vec!["1,x,one,,", "2,x,three,1,y,two", "3,x,four,1,z"]
+        );
+        let args = vec!["left=ra,rb".to_string(), "right=a".to_string()];
+        assert!(CsvInnerJoin::try_from(args).is_err());
+
+        // waiting records beyond the memory budget are spilled to disk
+        let dir = std::env::temp_dir().join(format!("join-spill-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spill = format!("spill={}", dir.display());
+        assert_eq!(join(&["memory=1", &spill]), enrich);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // more runs than are merged at once, every odd record refers to the next one
+        let mut csv = "id,value,ref\n".to_string();
+        for i in 0..100 {
+            let reference = if i % 2 == 1 {
+                (i + 1).to_string()
+            } else {
+                String::new()
+            };
+            csv.push_str(&format!("{},v{},{}\n", i, i, reference));
+        }
+        let path = std::env::temp_dir().join(format!("join-runs-{}.csv", std::process::id()));
+        std::fs::write(&path, csv).unwrap();
+        let records = join_file(&path, &["memory=1", &spill]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 51);
+        assert!(records.contains(&"0,v0,".to_string()));
+        assert!(records.contains(&"1,v1,2,v2".to_string()));
+        assert!(records.contains(&"99,v99,100".to_string()));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(CsvInnerJoin::try_from(vec!["memory=lots".to_string()]).is_err());
+
+        // join against a lookup table from other files
+        let dir = std::env::temp_dir().join(format!("join-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lookup.csv"), "key,name\n3,three\n5,five\n").unwrap();
+        let lookup = format!("lookup={}/*.csv", dir.display());
+        assert_eq!(
+            join(&[
+                &lookup,
+                "left=ref",
+                "right=key",
+                "columns=name",
+                "kind=anti"
+            ]),
+            vec!["1,hi,", "3,world,"]
+        );
+        assert_eq!(
+            join(&[
+                &lookup,
+                "left=ref",
+                "right=key",
+                "columns=name",
+                "kind=inner"
+            ]),
+            vec!["2,hello,3,three", "4,test,5,five"]
+        );
+        assert_eq!(
+            join(&[&lookup, "left=ref", "right=key", "columns=name"]),
+            vec!["1,hi,", "2,hello,3,three", "3,world,", "4,test,5,five"]
+        );
+        assert_eq!(
+            join(&[
+                &lookup,
+                "left=ref",
+                "right=key",
+                "columns=name",
+                "kind=left"
+            ]),
+            vec!["1,hi,,", "2,hello,3,three", "3,world,,", "4,test,5,five"]
+        );
+
+        assert!(CsvInnerJoin::try_from(vec![lookup, "right=missing".to_string()]).is_err());
+        assert!(CsvInnerJoin::try_from(vec!["kind=outer".to_string()]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        use crate::json::*;
+        use std::io::{Cursor, Read};
+
+        let events = r#"{"level":"info","user":{"name":"ann"},"msg":"started"}
+{"level":"error","user":{"name":"bob"},"msg":"disk full"}
+
+not json
+{"level":"error","user":{"name":"ann"},"msg":"timeout","code":504}
+"#;
+        let reader = Box::new(Cursor::new(events)) as Box<dyn Read + Send + Sync>;
+        let values: Vec<_> = JsonLines::default()
+            .transform(FlowFile::new(reader))
+            .collect();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[2].meta.source(), ":4");
+
+        let ann = FieldEquals::try_from(vec!["/user/name".to_string(), "ann".to_string()]).unwrap();
+        let contains = Contains::try_from(vec!["504".to_string()]).unwrap();
+        let found: Vec<_> = values
+            .into_iter()
+            .flat_map(|v| ann.transform(v))
+            .flat_map(|v| contains.transform(v))
+            .flat_map(|v| ToJson::default().transform(v))
+            .map(|v| v.data)
+            .collect();
+        assert_eq!(
+            found,
+            vec![r#"{"code":504,"level":"error","msg":"timeout","user":{"name":"ann"}}"#]
+        );
+
+        let dir = std::env::temp_dir().join(format!("json-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let valid: Vec<_> = events.lines().filter(|l| *l != "not json").collect();
+        std::fs::write(dir.join("events.jsonl"), valid.join("\n")).unwrap();
+        let src = format!(
+            r#"
+glob: Glob {}/*.jsonl
+unpack: Unpack
+json: JsonLines
+errors: FieldEquals level error
+str: ToJson
+sink: Nullify
+
+glob -> unpack -> json -> errors -> str -> sink
+"#,
+            dir.display()
+        );
+        let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+        assert_eq!(stats.total(), 2);
+        assert!(stats.stages().iter().all(|s| s.failures() == 0));
+
+        let src = "Glob testcase.csv\nUnpack\nCsvRecords\nToJson\nNullify\n";
+        assert!(Pipeline::parse(src, &Registry::default()).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet() {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::Int64Type;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let dir = std::env::temp_dir().join(format!("parquet-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("in.csv"),
+            "id,value,ref\n1,hi,\n2,hello,3\nx,bad,\n4,test,5\n",
+        )
+        .unwrap();
+
+        let src = format!(
+            "Glob {}/in.csv\nUnpack\nCsvRecords\nWriteParquet {}/out-{{seq}}.parquet schema=id:int64,ref:int64,value rows_per_file=2 row_group=1\n",
+            dir.display(),
+            dir.display()
+        );
+        let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+
+        let read = |name: &str| {
+            let file = std::fs::File::open(dir.join(name)).unwrap();
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+            let groups = reader.metadata().num_row_groups();
+            let batches: Vec<_> = reader.build().unwrap().map(Result::unwrap).collect();
+            (groups, batches)
+        };
+
+        // a row group per row
+        let (groups, batches) = read("out-0.parquet");
+        assert_eq!(groups, 2);
+        let ids = batches[0].column(0).as_primitive::<Int64Type>();
+        assert_eq!(ids.values().to_vec(), vec![1, 2]);
+        assert!(batches[0].column(1).is_null(0));
+        assert_eq!(batches[0].column(2).as_string::<i32>().value(1), "hello");
+
+        // the record with a bad id is failed
+        let (groups, _) = read("out-1.parquet");
+        assert_eq!(groups, 1);
+        assert!(!dir.join("out-2.parquet").exists());
+
+        assert!(WriteParquet::<Record>::try_from(vec![
+            "out.parquet".to_string(),
+            "rows_per_file=1".to_string()
+        ])
+        .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rolling_write() {
+        use crate::rolling::*;
+
+        let dir = std::env::temp_dir().join(format!("rolling-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let entries = |path: &std::path::Path| {
+            let gz = flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap());
+            tar::Archive::new(gz).entries().unwrap().count()
+        };
+        let item = |i: usize| {
+            let mut meta = FlowFile::new(()).meta;
+            meta.add_source(&format!("item-{}", i));
+            FlowFile {
+                data: format!("{}", i),
+                meta,
+            }
+        };
+
+        let template = format!("{}/out-{{seq}}.tar.gz", dir.display());
+        let sink = RollingWrite::try_from(vec![template.clone(), "max_items=2".into()]).unwrap();
+        for i in 0..5 {
+            sink.close(item(i));
+        }
+        // full files are complete right away, the last one when the sink is dropped
+        assert!(dir.join("out-1.tar.gz").exists());
+        assert!(dir.join("out-2.tar.gz.partial").exists());
+        drop(sink);
+
+        let counts: Vec<_> = (0..3)
+            .map(|i| entries(&dir.join(format!("out-{}.tar.gz", i))))
+            .collect();
+        assert_eq!(counts, vec![2, 2, 1]);
+        assert!(!dir.join("out-2.tar.gz.partial").exists());
+
+        // existing files are not overwritten, and files are completed once their time is up
+        let sink = RollingWrite::try_from(vec![template.clone(), "interval=50ms".into()]).unwrap();
+        sink.close(item(5));
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert_eq!(entries(&dir.join("out-3.tar.gz")), 1);
+        drop(sink);
+
+        // a partial file left by a crash is kept, and its name skipped
+        std::fs::write(dir.join("out-4.tar.gz.partial"), "stale").unwrap();
+        let sink = RollingWrite::try_from(vec![template, "max_items=1".into()]).unwrap();
+        sink.close(item(6));
+        assert_eq!(entries(&dir.join("out-5.tar.gz")), 1);
+        assert!(!dir.join("out-4.tar.gz").exists());
+        drop(sink);
+
+        assert!(RollingWrite::try_from(vec!["out.tar.gz".to_string()]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_attributes() {
+        let src = r#"
+glob: Glob testcase.csv Cargo.toml
+unpack: Unpack
+lines: Lines
+tag: SetAttribute team=data
+team: AttributeEquals team data
+line: AttributeEquals line 2
+split: SplitByAttribute filename testcase.csv
+sink: Nullify
+
+glob -> unpack -> lines -> tag -> team -> line -> split -> sink
+"#;
+        let registry = Registry::default();
+        let pipeline = Pipeline::parse(src, &registry).unwrap();
+
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+
+        assert_eq!(stats.total(), 1);
+    }
+
+    #[test]
+    fn test_executor() {
+        let g = Glob::try_from(vec!["testcase.csv".to_string(), "Cargo.toml".to_string()]).unwrap();
+        let s = SplitByExt::from(vec!["csv".to_string(), "toml".to_string()]);
+        let n = Nullify::from(vec![]);
+
+        let stats = Stats::new();
+
+        let mut branches = Flow::start(g, 1, &stats)
+            .transform(Unpack::default(), 2, 1)
+            .split(s, 2, 1);
+        let toml = branches.pop().unwrap().transform(Lines::default(), 1, 1);
+        let csv = branches
+            .pop()
+            .unwrap()
+            .transform(Csv::default(), 2, 1)
+            .transform(ToString::default(), 2, 1);
+
+        Flow::merge(vec![csv, toml], 1)
+            .unwrap()
+            .close(n, 2)
+            .join()
+            .unwrap();
+
+        let toml = std::fs::read_to_string("Cargo.toml").unwrap();
+        assert_eq!(stats.total(), 4 + toml.lines().count() as u64);
+
+        let stages = stats.stages();
+        let unpack = stages.iter().find(|s| s.name() == "2 Unpack").unwrap();
+        assert_eq!(unpack.items_in(), 2);
+        assert_eq!(unpack.items_out(), 2);
+        assert_eq!(unpack.failures(), 0);
+
+        let sink = stages.iter().find(|s| s.name() == "7 Nullify").unwrap();
+        assert_eq!(sink.items_in(), stats.total());
+        assert_eq!(sink.latency().count(), stats.total());
+    }
+
+    #[test]
+    fn test_partitioned_write() {
+        let dir = std::env::temp_dir().join(format!("partitions-{}", std::process::id()));
+        let src = format!(
+            "Glob testcase.csv\nUnpack\nCsvRecords\nFieldToAttribute ref\nToString\n\
+             PartitionedWrite {} by=source,ref",
+            dir.display()
+        );
+        let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+        pipeline.run(&Stats::new()).unwrap();
+
+        let entries = |partition: &str| {
+            let path = dir.join(partition).join("part-0.tar.gz");
+            let gz = flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap());
+            tar::Archive::new(gz).entries().unwrap().count()
+        };
+        // rows without a reference go to the default partition
+        assert_eq!(
+            entries("source=testcase.csv/ref=__HIVE_DEFAULT_PARTITION__"),
+            2
+        );
+        assert_eq!(entries("source=testcase.csv/ref=3"), 1);
+        assert_eq!(entries("source=testcase.csv/ref=5"), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // with a single open archive, a partition written to again continues in a new part
+        let args = vec![
+            dir.display().to_string(),
+            "by=k".into(),
+            "max_open=1".into(),
+        ];
+        let write = PartitionedWrite::try_from(args).unwrap();
+        for k in &["a", "b", "a"] {
+            let mut item = FlowFile::new(k.to_string());
+            item.meta.add_source(k);
+            item.meta.set_attribute("k", k.to_string());
+            write.close(item);
+        }
+        CloseTransform::finish(&write).unwrap();
+        let parts = |partition: &str| {
+            let dir = dir.join(partition);
+            let mut counts = vec![];
+            while let Ok(file) =
+                std::fs::File::open(dir.join(format!("part-{}.tar.gz", counts.len())))
+            {
+                let gz = flate2::read::GzDecoder::new(file);
+                counts.push(tar::Archive::new(gz).entries().unwrap().count());
+            }
+            counts
+        };
+        assert_eq!(parts("k=a"), vec![1, 1]);
+        assert_eq!(parts("k=b"), vec![1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(PartitionedWrite::try_from(vec!["out".to_string()]).is_err());
+        let args = vec![
+            "out".to_string(),
+            "by=date".to_string(),
+            "max_open=0".to_string(),
+        ];
+        assert!(PartitionedWrite::try_from(args).is_err());
+    }
+
+    #[test]
+    fn test_text_sinks() {
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("text-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // in source order the file is copied as it is, also when compressed
+        let lines = dir.join("lines.txt.gz");
+        let src = format!(
+            "Glob testcase.csv\nUnpack\nLines\nWriteLines {} order=source",
+            lines.display()
+        );
+        Pipeline::parse(&src, &Registry::default())
+            .unwrap()
+            .run(&Stats::new())
+            .unwrap();
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&lines).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, std::fs::read_to_string("testcase.csv").unwrap());
+
+        let csv = dir.join("out.csv");
+        let src = format!(
+            "Glob testcase.csv\nUnpack\nCsvRecords\nSelect value id\nWriteCsv {} delimiter=; order=source",
+            csv.display()
+        );
+        Pipeline::parse(&src, &Registry::default())
+            .unwrap()
+            .run(&Stats::new())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&csv).unwrap(),
+            "value;id\nhi;1\nhello;2\nworld;3\ntest;4\n"
+        );
+
+        // lines beyond the buffer fail instead of growing it
+        let limited = dir.join("limited.txt");
+        let src = format!(
+            "Glob testcase.csv\nUnpack\nLines\nWriteLines {} order=source buffer=20",
+            limited.display()
+        );
+        let stats = Stats::new();
+        Pipeline::parse(&src, &Registry::default())
+            .unwrap()
+            .run(&stats)
+            .unwrap();
+        let text = std::fs::read_to_string(&limited).unwrap();
+        assert!(!text.is_empty() && text.len() <= 20);
+        assert!(stats.stages().iter().any(|s| s.failures() > 0));
+
+        assert!(WriteLines::try_from(vec!["order=source".to_string()]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_finish() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // the sink is finished once, after its last item, and an error counts as a failure
+        struct Finish(Arc<AtomicUsize>);
+        impl From<Vec<String>> for Finish {
+            fn from(_args: Vec<String>) -> Self {
+                Self(Arc::default())
+            }
+        }
+        impl CloseTransform for Finish {
+            type Input = String;
+            fn close(&self, _input: FlowFile<String>) {
+                assert_eq!(self.0.load(Ordering::SeqCst), 0);
+            }
+            fn finish(&self) -> crate::error::Result<()> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Err(crate::error::Error::Args("finish".into()))
+            }
+        }
+
+        let stats = Stats::new();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let result = Flow::start(
+            Glob::try_from(vec!["Cargo.toml".to_string()]).unwrap(),
+            1,
+            &stats,
+        )
+        .transform(Unpack::default(), 1, 1)
+        .transform(Lines::default(), 1, 1)
+        .close(Finish(finished.clone()), 3)
+        .join();
+        assert!(result.is_err());
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        let stages = stats.stages();
+        let sink = stages.iter().find(|s| s.name() == "4 Finish").unwrap();
+        assert_eq!(sink.failures(), 1);
+
+        // so does a pipeline with such a sink, next to one that finishes
+        let mut registry = Registry::default();
+        registry.register_close::<Finish>("Finish");
+        let src = "glob: Glob Cargo.toml\nunpack: Unpack\nsplit: SplitByExt toml\n\
+                   lines: Lines\nfinish: Finish\nnull: Nullify\n\
+                   glob -> unpack -> split\nsplit -> lines -> finish\nsplit -> null\n";
+        let pipeline = Pipeline::parse(src, &registry).unwrap();
+        assert!(pipeline.run(&Stats::new()).is_err());
+
+        // the archive is complete when the pipeline is done
+        let path = std::env::temp_dir().join(format!("finish-{}.tar.gz", std::process::id()));
+        let src = format!("Glob Cargo.toml\nUnpack\nLines\nWrite {}", path.display());
+        let pipeline = Pipeline::parse(&src, &Registry::default()).unwrap();
+        let stats = Stats::new();
+        pipeline.run(&stats).unwrap();
+
+        let gz = flate2::read::GzDecoder::new(std::fs::File::open(&path).unwrap());
+        let entries = tar::Archive::new(gz).entries().unwrap().count();
+        let toml = std::fs::read_to_string("Cargo.toml").unwrap();
+        assert_eq!(entries, toml.lines().count());
+        assert!(stats.stages().iter().all(|s| s.failures() == 0));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_metrics_endpoint() {
+        use crate::metrics::MetricsServer;
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        let src = "Glob Cargo.toml\nUnpack\nLines\nNullify";
+        let pipeline = Pipeline::parse(src, &Registry::default()).unwrap();
+
+        let stats = Stats::new();
+        let server = MetricsServer::serve(&stats, "127.0.0.1:0").unwrap();
+        pipeline.run(&stats).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let lines = std::fs::read_to_string("Cargo.toml")
+            .unwrap()
+            .lines()
+            .count();
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(&format!("ingest_items_total {}\n", lines)));
+        assert!(response.contains("ingest_stage_items_in_total{stage=\"2 Unpack\"} 1\n"));
+        assert!(response.contains(&format!(
+            "ingest_stage_latency_seconds_count{{stage=\"4 Nullify\"}} {}\n",
+            lines
+        )));
+
+        assert!(get("/other").starts_with("HTTP/1.1 404"));
+
+        // a client that sends nothing blocks neither other requests nor the shutdown
+        let _silent = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(get("/metrics").starts_with("HTTP/1.1 200 OK"));
+        let start = std::time::Instant::now();
+        drop(server);
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
     }
 }
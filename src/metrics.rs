@@ -0,0 +1,186 @@
+use crate::framework::{StageStats, Stats, WeakStats};
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// A client that sends or reads nothing does not keep its connection longer than this
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Render the stats in the Prometheus text exposition format
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# TYPE ingest_items_total counter");
+    let _ = writeln!(out, "ingest_items_total {}", stats.total());
+    let _ = writeln!(out, "# TYPE ingest_uptime_seconds gauge");
+    let _ = writeln!(
+        out,
+        "ingest_uptime_seconds {}",
+        stats.elapsed().as_secs_f64()
+    );
+
+    let stages = stats.stages();
+    counter(&mut out, "ingest_stage_items_in_total", &stages, |s| {
+        s.items_in()
+    });
+    counter(&mut out, "ingest_stage_items_out_total", &stages, |s| {
+        s.items_out()
+    });
+    counter(&mut out, "ingest_stage_failures_total", &stages, |s| {
+        s.failures()
+    });
+    counter(&mut out, "ingest_stage_bytes_total", &stages, |s| s.bytes());
+
+    let _ = writeln!(out, "# TYPE ingest_stage_latency_seconds histogram");
+    for stage in &stages {
+        let label = escape(stage.name());
+        let latency = stage.latency();
+        for (le, count) in latency.cumulative() {
+            let _ = writeln!(
+                out,
+                "ingest_stage_latency_seconds_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                label,
+                le.as_secs_f64(),
+                count
+            );
+        }
+        let count = latency.count();
+        let _ = writeln!(
+            out,
+            "ingest_stage_latency_seconds_bucket{{stage=\"{}\",le=\"+Inf\"}} {}",
+            label, count
+        );
+        let _ = writeln!(
+            out,
+            "ingest_stage_latency_seconds_sum{{stage=\"{}\"}} {}",
+            label,
+            stage.time().as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "ingest_stage_latency_seconds_count{{stage=\"{}\"}} {}",
+            label, count
+        );
+    }
+
+    out
+}
+
+fn counter(
+    out: &mut String,
+    metric: &str,
+    stages: &[Arc<StageStats>],
+    value: impl Fn(&StageStats) -> u64,
+) {
+    let _ = writeln!(out, "# TYPE {} counter", metric);
+    for stage in stages {
+        let label = escape(stage.name());
+        let _ = writeln!(out, "{}{{stage=\"{}\"}} {}", metric, label, value(stage));
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Serves `/metrics` until dropped. It does not keep the stats alive, once they are dropped
+// the endpoint answers with 503. Every connection is answered on its own thread, so a slow
+// client neither delays other requests nor the drop.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn serve<A: ToSocketAddrs>(stats: &Stats, addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let weak = stats.downgrade();
+        let stopped = stop.clone();
+        let thread = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let weak = weak.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = respond(stream, &weak) {
+                                log::debug!("metrics request failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("metrics endpoint: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake up the blocking accept
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn respond(stream: TcpStream, stats: &WeakStats) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => match stats.upgrade() {
+            Some(stats) => ("200 OK", render(&stats)),
+            None => ("503 Service Unavailable", String::new()),
+        },
+        (Some("GET"), _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
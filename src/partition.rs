@@ -0,0 +1,283 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::rolling::date;
+use crate::transformers::{options, source_file, TarGz};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+// Directory name of items without a value for a partition key, as used by Hive
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+const MAX_OPEN: usize = 64;
+
+enum Key {
+    // the source file of the item, without the line number
+    Source,
+    // the modification date of the source file, or today when it is unknown
+    Date,
+    Attribute(String),
+}
+
+impl Key {
+    fn name(&self) -> &str {
+        match self {
+            Key::Source => "source",
+            Key::Date => "date",
+            Key::Attribute(key) => key,
+        }
+    }
+
+    fn value(&self, meta: &FlowFileMeta) -> Option<String> {
+        match self {
+            Key::Source => Some(source_file(meta).to_string()),
+            Key::Date => match meta.attribute(MTIME) {
+                Some(Attribute::Time(mtime)) => Some(date(*mtime)),
+                _ => Some(date(SystemTime::now())),
+            },
+            Key::Attribute(key) => meta.attribute(key).map(Attribute::to_string),
+        }
+    }
+}
+
+// Escape the characters Hive escapes in partition values, so a value is a single directory
+fn escape(value: &str) -> String {
+    if value.is_empty() {
+        return DEFAULT_PARTITION.to_string();
+    }
+
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_control() || "\"#%'*/:=?\\^[]{}".contains(c) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// Writes items to a tar.gz archive per partition, like `Write`. The first argument is the output
+// directory, the partition keys are given as `by=date,source`, or any attribute name. Partitions
+// are Hive style directories, e.g. `out/date=2021-06-30/source=data.csv/part-0.tar.gz`.
+//
+// Use `FieldToAttribute` to partition on the value of a column.
+//
+// Partitions are written in parallel, items only wait for others of the same partition. At most
+// `max_open=64` archives are open, beyond that the least recently written one is completed, and
+// the next item of its partition starts a new part. The other archives are completed when the
+// flow ends.
+pub struct PartitionedWrite {
+    dir: PathBuf,
+    keys: Vec<Key>,
+    max_open: usize,
+    partitions: RwLock<HashMap<PathBuf, Partition>>,
+    // ticks on every item, for the least recently written partition
+    clock: AtomicU64,
+    // the first error completing a partition before the flow ends
+    error: Mutex<Option<Error>>,
+}
+
+struct Partition {
+    // taken when the archive is complete
+    archive: Arc<Mutex<Option<TarGz>>>,
+    used: AtomicU64,
+}
+
+impl TryFrom<Vec<String>> for PartitionedWrite {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        if args.is_empty() || args[0].contains('=') {
+            return Err(Error::Args(
+                "PartitionedWrite needs an output directory".into(),
+            ));
+        }
+        let dir = PathBuf::from(args.remove(0));
+
+        let options = options("PartitionedWrite", &args, &["by", "max_open"])?;
+        let keys: Vec<_> = match options.get("by") {
+            Some(by) => by
+                .split(',')
+                .filter(|k| !k.is_empty())
+                .map(|k| match k {
+                    "source" => Key::Source,
+                    "date" => Key::Date,
+                    key => Key::Attribute(key.to_string()),
+                })
+                .collect(),
+            None => vec![],
+        };
+        if keys.is_empty() {
+            return Err(Error::Args(
+                "PartitionedWrite needs partition keys, e.g. `by=date`".into(),
+            ));
+        }
+
+        let max_open = match options.get("max_open") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(Error::Args(format!(
+                        "`max_open` needs a positive number, got `{}`",
+                        v
+                    )))
+                }
+            },
+            None => MAX_OPEN,
+        };
+
+        Ok(Self {
+            dir,
+            keys,
+            max_open,
+            partitions: RwLock::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            error: Mutex::new(None),
+        })
+    }
+}
+
+impl PartitionedWrite {
+    fn partition(&self, meta: &FlowFileMeta) -> PathBuf {
+        let mut path = self.dir.clone();
+        for key in &self.keys {
+            let value = key.value(meta).unwrap_or_default();
+            path.push(format!("{}={}", escape(key.name()), escape(&value)));
+        }
+        path
+    }
+
+    // The archive of a partition, created on its first item
+    fn archive(&self, partition: &Path) -> Result<Arc<Mutex<Option<TarGz>>>> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(p) = self.partitions.read().unwrap().get(partition) {
+            p.used.store(now, Ordering::Relaxed);
+            return Ok(p.archive.clone());
+        }
+
+        let mut partitions = self.partitions.write().unwrap();
+        if let Some(p) = partitions.get(partition) {
+            p.used.store(now, Ordering::Relaxed);
+            return Ok(p.archive.clone());
+        }
+
+        // complete the least recently written archive when too many are open
+        let evicted = if partitions.len() >= self.max_open {
+            partitions
+                .iter()
+                .min_by_key(|(_, p)| p.used.load(Ordering::Relaxed))
+                .map(|(path, _)| path.clone())
+                .and_then(|path| partitions.remove(&path))
+        } else {
+            None
+        };
+        let archive = std::fs::create_dir_all(partition)
+            .map_err(Error::from)
+            .and_then(|_| create(partition))
+            .map(|ar| Arc::new(Mutex::new(Some(ar))));
+        if let Ok(archive) = &archive {
+            let used = AtomicU64::new(now);
+            let archive = archive.clone();
+            partitions.insert(partition.to_path_buf(), Partition { archive, used });
+        }
+        drop(partitions);
+
+        // completed without blocking the other partitions, items written to it meanwhile are
+        // still in it
+        if let Some(p) = evicted {
+            if let Some(ar) = p.archive.lock().unwrap().take() {
+                if let Err(e) = ar.finish() {
+                    log::error!("cannot complete a partition: {}", e);
+                    self.error.lock().unwrap().get_or_insert(e);
+                }
+            }
+        }
+        archive
+    }
+
+    // Whether the archive is still the one of the partition, rather than evicted
+    fn is_open(&self, partition: &Path, archive: &Arc<Mutex<Option<TarGz>>>) -> bool {
+        match self.partitions.read().unwrap().get(partition) {
+            Some(p) => Arc::ptr_eq(&p.archive, archive),
+            None => false,
+        }
+    }
+}
+
+// Archives of earlier runs, or evicted in this one, are not overwritten, the next number is
+// taken instead
+fn create(partition: &Path) -> Result<TarGz> {
+    let mut seq = 0;
+    let path = loop {
+        let path = partition.join(format!("part-{}.tar.gz", seq));
+        if !path.exists() {
+            break path;
+        }
+        seq += 1;
+    };
+    TarGz::create(&path)
+}
+
+impl CloseTransform for PartitionedWrite {
+    type Input = String;
+
+    fn close(&self, input: FlowFile<Self::Input>) {
+        let FlowFile { data, meta } = input;
+
+        let partition = self.partition(&meta);
+        let result = loop {
+            let archive = match self.archive(&partition) {
+                Ok(archive) => archive,
+                Err(e) => return meta.fail(e),
+            };
+            if let Some(ar) = archive.lock().unwrap().as_mut() {
+                break ar.append(&meta, data.as_bytes());
+            }
+
+            // an evicted archive was completed, the item goes to a new part
+            if self.is_open(&partition, &archive) {
+                break Err(std::io::Error::other("the archive is already complete"));
+            }
+        };
+        if let Err(e) = result {
+            meta.fail(e);
+        }
+    }
+
+    // Complete every archive, also when one of them fails
+    fn finish(&self) -> Result<()> {
+        let mut result = match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+        let archives: Vec<_> = self
+            .partitions
+            .read()
+            .unwrap()
+            .values()
+            .map(|p| p.archive.clone())
+            .collect();
+        for archive in archives {
+            if let Some(ar) = archive.lock().unwrap().take() {
+                result = result.and(ar.finish());
+            }
+        }
+        result
+    }
+}
+
+impl Drop for PartitionedWrite {
+    fn drop(&mut self) {
+        if let Err(e) = CloseTransform::finish(self) {
+            log::error!("cannot complete the partitions: {}", e);
+        }
+    }
+}
@@ -0,0 +1,694 @@
+use crate::error::Error;
+use crate::framework::*;
+use crate::registry::*;
+use crate::transformers::{move_dirs, Unpack};
+use crate::watch::{matches_moved, Watch};
+
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct PipelineError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl PipelineError {
+    fn new(line: usize, message: String) -> Self {
+        Self { line, message }
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub line: usize,
+    pub id: Option<String>,
+    pub name: String,
+    pub args: Vec<String>,
+    pub junction: Option<usize>,
+    pub on_error: bool,
+    pub next: Vec<usize>,
+    // `@threads=N` and `@capacity=N`, for running the step in a `Flow`
+    pub threads: Option<usize>,
+    pub capacity: Option<usize>,
+}
+
+// Parse the pipeline description. The linear format has one transformer per line:
+//
+//     Glob *.csv "*.toml"
+//     Unpack
+//     Junction:3 SplitByExt csv toml
+//     ...
+//
+// A `Junction:N` sends its first branch through the next N steps and its second branch through
+// the single step after those. The remaining steps are shared by both branches.
+//
+// The graph format declares named nodes and connects them with `->` chains. A junction sends
+// its branches to its outgoing edges in the order they are declared:
+//
+//     glob: Glob *.csv "*.toml"
+//     unpack: Unpack
+//     split: SplitByExt csv toml
+//     ...
+//     glob -> unpack -> split
+//     split -> csv -> contains -> str -> write
+//     split -> lines -> write
+//
+// In both formats, a line `OnError Transformer args..` names the sink receiving the failures.
+// Lines starting with `#` are comments.
+//
+// Every step runs on its own threads and takes its input from a bounded queue. A step can set
+// the number of threads with `@threads=N` (by default one per core, sources and junctions run
+// on one thread) and the size of its queue with `@capacity=N` (1024 items by default).
+pub fn parse(src: &str) -> Result<Vec<Step>, PipelineError> {
+    let mut lines = vec![];
+    let mut on_error = None;
+    for (i, line) in src.lines().enumerate() {
+        let line_no = i + 1;
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let words =
+            shell_words::split(line).map_err(|e| PipelineError::new(line_no, format!("{}", e)))?;
+        if words.first().map(String::as_str) == Some("OnError") {
+            if let Some(other) = &on_error {
+                let msg = format!("`OnError` is already declared on line {}", other);
+                return Err(PipelineError::new(line_no, msg));
+            }
+            on_error = Some(line_no);
+        }
+        if !words.is_empty() {
+            lines.push((line_no, words));
+        }
+    }
+
+    let (errors, lines): (Vec<_>, Vec<_>) = lines.into_iter().partition(|(_, w)| w[0] == "OnError");
+
+    let is_graph = lines
+        .iter()
+        .any(|(_, words)| words.iter().any(|w| w == "->") || words[0].ends_with(':'));
+
+    let mut steps = if is_graph {
+        parse_graph(lines)?
+    } else {
+        parse_linear(lines)?
+    };
+
+    for (line_no, mut words) in errors {
+        if words.len() < 2 {
+            let msg = "`OnError` needs a transformer".to_string();
+            return Err(PipelineError::new(line_no, msg));
+        }
+        words.remove(0);
+        if stage_options(line_no, &mut words)? != (None, None) {
+            let msg = "`OnError` runs on the threads of the failing steps".to_string();
+            return Err(PipelineError::new(line_no, msg));
+        }
+        steps.push(Step {
+            line: line_no,
+            id: None,
+            name: words.remove(0),
+            args: words,
+            junction: None,
+            on_error: true,
+            next: vec![],
+            threads: None,
+            capacity: None,
+        });
+    }
+
+    Ok(steps)
+}
+
+// Take the `@key=value` options of a step out of its words
+fn stage_options(
+    line_no: usize,
+    words: &mut Vec<String>,
+) -> Result<(Option<usize>, Option<usize>), PipelineError> {
+    let (mut threads, mut capacity) = (None, None);
+    for word in words.iter().filter(|w| w.starts_with('@')) {
+        let (key, value) = word[1..].split_once('=').unwrap_or((&word[1..], ""));
+        let value = match value.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                let msg = format!("`@{}` needs a positive number, got `{}`", key, value);
+                return Err(PipelineError::new(line_no, msg));
+            }
+        };
+        match key {
+            "threads" => threads = value,
+            "capacity" => capacity = value,
+            _ => {
+                let msg = format!("unknown option `@{}`, expected @threads or @capacity", key);
+                return Err(PipelineError::new(line_no, msg));
+            }
+        }
+    }
+    words.retain(|w| !w.starts_with('@'));
+    if words.is_empty() {
+        return Err(PipelineError::new(line_no, "options without a step".into()));
+    }
+    Ok((threads, capacity))
+}
+
+fn parse_linear(lines: Vec<(usize, Vec<String>)>) -> Result<Vec<Step>, PipelineError> {
+    let mut steps = vec![];
+
+    for (line_no, mut words) in lines {
+        let (threads, capacity) = stage_options(line_no, &mut words)?;
+        let mut name = words.remove(0);
+        let junction = if let Some(num) = name.strip_prefix("Junction:") {
+            let num = num
+                .parse()
+                .map_err(|_| PipelineError::new(line_no, format!("bad junction `{}`", name)))?;
+            if words.is_empty() {
+                return Err(PipelineError::new(line_no, "junction without name".into()));
+            }
+            name = words.remove(0);
+            Some(num)
+        } else {
+            None
+        };
+
+        steps.push(Step {
+            line: line_no,
+            id: None,
+            name,
+            args: words,
+            junction,
+            on_error: false,
+            next: vec![],
+            threads,
+            capacity,
+        });
+    }
+
+    let seq: Vec<_> = (0..steps.len()).collect();
+    link(&seq, &mut steps)?;
+
+    Ok(steps)
+}
+
+fn parse_graph(lines: Vec<(usize, Vec<String>)>) -> Result<Vec<Step>, PipelineError> {
+    let mut steps: Vec<Step> = vec![];
+    let mut edges = vec![];
+
+    for (line_no, mut words) in lines {
+        if words.iter().any(|w| w == "->") {
+            edges.push((line_no, words));
+            continue;
+        }
+
+        let id = match words[0].strip_suffix(':') {
+            Some(id) if !id.is_empty() && words.len() > 1 => id.to_string(),
+            _ => {
+                let msg = "expected `name: Transformer args..` or `a -> b`".to_string();
+                return Err(PipelineError::new(line_no, msg));
+            }
+        };
+        if let Some(other) = steps.iter().find(|s| s.id.as_deref() == Some(id.as_str())) {
+            let msg = format!("`{}` is already declared on line {}", id, other.line);
+            return Err(PipelineError::new(line_no, msg));
+        }
+        if words[1].starts_with("Junction:") {
+            let msg = "`Junction:N` is not used in the graph format".to_string();
+            return Err(PipelineError::new(line_no, msg));
+        }
+
+        words.remove(0);
+        let (threads, capacity) = stage_options(line_no, &mut words)?;
+        steps.push(Step {
+            line: line_no,
+            id: Some(id),
+            name: words.remove(0),
+            args: words,
+            junction: None,
+            on_error: false,
+            next: vec![],
+            threads,
+            capacity,
+        });
+    }
+
+    for (line_no, words) in edges {
+        let chain = words.split(|w| w == "->");
+        let mut prev: Option<usize> = None;
+
+        for ids in chain {
+            let id = match ids {
+                [id] => id,
+                _ => {
+                    let msg = "expected a single node name between `->`".to_string();
+                    return Err(PipelineError::new(line_no, msg));
+                }
+            };
+            let n = steps
+                .iter()
+                .position(|s| s.id.as_ref() == Some(id))
+                .ok_or_else(|| PipelineError::new(line_no, format!("unknown node `{}`", id)))?;
+
+            if let Some(p) = prev {
+                if steps[p].next.contains(&n) {
+                    let msg = format!(
+                        "duplicate edge `{}` -> `{}`",
+                        steps[p].id.as_ref().unwrap(),
+                        id
+                    );
+                    return Err(PipelineError::new(line_no, msg));
+                }
+                steps[p].next.push(n);
+            }
+            prev = Some(n);
+        }
+    }
+
+    Ok(steps)
+}
+
+fn link(seq: &[usize], steps: &mut [Step]) -> Result<(), PipelineError> {
+    for (k, &i) in seq.iter().enumerate() {
+        let rest = &seq[k + 1..];
+
+        let pos = match steps[i].junction {
+            None => {
+                if let Some(&n) = rest.first() {
+                    add_edge(&mut steps[i], n);
+                }
+                continue;
+            }
+            Some(pos) => pos,
+        };
+
+        if rest.len() <= pos {
+            let msg = format!(
+                "`Junction:{}` needs at least {} steps after it",
+                pos,
+                pos + 1
+            );
+            return Err(PipelineError::new(steps[i].line, msg));
+        }
+
+        let tail = &rest[pos + 1..];
+        let first: Vec<_> = rest[..pos].iter().chain(tail).copied().collect();
+        let second: Vec<_> = rest[pos..=pos].iter().chain(tail).copied().collect();
+
+        for branch in [first, second].iter() {
+            if let Some(&n) = branch.first() {
+                add_edge(&mut steps[i], n);
+            }
+            link(branch, steps)?;
+        }
+
+        return Ok(());
+    }
+
+    Ok(())
+}
+
+fn add_edge(step: &mut Step, to: usize) {
+    if !step.next.contains(&to) {
+        step.next.push(to);
+    }
+}
+
+struct Source {
+    name: String,
+    start: Box<dyn DynStart>,
+    next: usize,
+    capacity: usize,
+}
+
+struct Node {
+    name: String,
+    stage: Stage,
+    next: Vec<usize>,
+    threads: usize,
+    capacity: usize,
+}
+
+// Feeds the failures to the `OnError` sink of the pipeline
+struct Errors(Box<dyn DynClose>);
+
+impl ErrorSink for Errors {
+    fn fail(&self, input: FlowFile<Failure>) {
+        let FlowFile { data, meta } = input;
+        let data = Box::new(data) as AnyData;
+        self.0.close(FlowFile { data, meta })
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        self.0.finish()
+    }
+}
+
+pub struct Pipeline {
+    sources: Vec<Source>,
+    // in topological order, so the inputs of a node are built before the node
+    nodes: Vec<Node>,
+    errors: Option<Arc<dyn ErrorSink>>,
+}
+
+// Order the steps so that every step comes after all of its inputs
+pub(crate) fn order(steps: &[Step]) -> Result<Vec<usize>, PipelineError> {
+    let mut incoming = vec![0; steps.len()];
+    for step in steps {
+        for &n in &step.next {
+            incoming[n] += 1;
+        }
+    }
+
+    let mut ready: Vec<_> = (0..steps.len()).filter(|&i| incoming[i] == 0).collect();
+    ready.reverse();
+
+    let mut order = Vec::with_capacity(steps.len());
+    while let Some(i) = ready.pop() {
+        order.push(i);
+        for &n in steps[i].next.iter().rev() {
+            incoming[n] -= 1;
+            if incoming[n] == 0 {
+                ready.push(n);
+            }
+        }
+    }
+
+    if let Some(i) = (0..steps.len()).find(|&i| incoming[i] > 0) {
+        let msg = format!("`{}` is part of a cycle", steps[i].name);
+        return Err(PipelineError::new(steps[i].line, msg));
+    }
+
+    Ok(order)
+}
+
+impl Step {
+    // Name the step the way the user wrote it down
+    fn describe(&self, index: usize) -> String {
+        match &self.id {
+            _ if self.on_error => format!("`OnError {}`", self.name),
+            Some(id) => format!("`{}` ({})", id, self.name),
+            None => format!("step {} `{}`", index + 1, self.name),
+        }
+    }
+}
+
+fn mismatch(
+    steps: &[Step],
+    registry: &Registry,
+    i: usize,
+    (t, producer): (DataType, usize),
+) -> PipelineError {
+    let accepted: Vec<_> = registry
+        .entries(&steps[i].name)
+        .iter()
+        .filter_map(|e| e.input)
+        .collect();
+    let expects = accepted
+        .iter()
+        .map(DataType::short_name)
+        .collect::<Vec<_>>()
+        .join(" or ");
+
+    let mut msg = format!(
+        "{} expects {} but {} on line {} produces {}",
+        steps[i].describe(i),
+        expects,
+        steps[producer].describe(producer),
+        steps[producer].line,
+        t.short_name()
+    );
+
+    let mut converters = registry.converters(t, &accepted);
+    if let Some(pos) = converters.iter().position(|&c| c == "ToString") {
+        converters.swap(0, pos);
+    }
+    if let Some(c) = converters.first() {
+        msg.push_str(&format!(", try inserting `{}` in between", c));
+    }
+
+    PipelineError::new(steps[i].line, msg)
+}
+
+// Check the steps form a valid pipeline and find the transformer for each of them
+pub fn resolve(steps: &[Step], registry: &Registry) -> Result<Vec<Entry>, PipelineError> {
+    if steps.is_empty() {
+        return Err(PipelineError::new(1, "empty pipeline".into()));
+    }
+
+    // data type flowing into each step, and the step producing it
+    let mut inputs: Vec<Option<(DataType, usize)>> = vec![None; steps.len()];
+    let mut entries: Vec<Option<Entry>> = vec![None; steps.len()];
+
+    for i in order(steps)? {
+        let step = &steps[i];
+        let input = match step.on_error {
+            true => Some((DataType::of::<Failure>(), i)),
+            false => inputs[i],
+        };
+
+        let entry = match registry.lookup(&step.name, input.map(|(t, _)| t)) {
+            Some(entry) => entry,
+            None => {
+                let entries = registry.entries(&step.name);
+                let msg = match input {
+                    _ if entries.is_empty() => format!("unknown transformer `{}`", step.name),
+                    Some(_) if step.on_error => {
+                        format!("`{}` does not accept failures", step.name)
+                    }
+                    Some(input) => return Err(mismatch(steps, registry, i, input)),
+                    None if entries.iter().any(|e| e.kind == Kind::Start) => {
+                        format!("{} cannot take input", step.describe(i))
+                    }
+                    None => format!("{} is not connected to any input", step.describe(i)),
+                };
+                return Err(PipelineError::new(step.line, msg));
+            }
+        };
+
+        if step.threads.is_some() && matches!(entry.kind, Kind::Start | Kind::Junction) {
+            let msg = format!("{} runs on a single thread", step.describe(i));
+            return Err(PipelineError::new(step.line, msg));
+        }
+
+        let expected = match entry.kind {
+            Kind::Close => 0..=0,
+            Kind::Junction => 1..=255,
+            Kind::Start | Kind::Transform => 1..=1,
+        };
+        if !expected.contains(&step.next.len()) {
+            let msg = match step.next.len() {
+                0 => format!("{} is not connected to any output", step.describe(i)),
+                n => format!("{} cannot have {} outputs", step.describe(i), n),
+            };
+            return Err(PipelineError::new(step.line, msg));
+        }
+
+        let output = entry.output.map(|t| (t, i));
+        for &n in &step.next {
+            match inputs[n] {
+                Some((t, p)) if Some(t) != entry.output => {
+                    let msg = format!(
+                        "{} receives {} from {} on line {} but {} from {} on line {}",
+                        steps[n].describe(n),
+                        t.short_name(),
+                        steps[p].describe(p),
+                        steps[p].line,
+                        entry.output.unwrap().short_name(),
+                        step.describe(i),
+                        step.line,
+                    );
+                    return Err(PipelineError::new(steps[n].line, msg));
+                }
+                _ => inputs[n] = output,
+            }
+        }
+
+        entries[i] = Some(*entry);
+    }
+
+    let entries: Vec<_> = entries.into_iter().map(Option::unwrap).collect();
+    check_moves(steps, &entries)?;
+    Ok(entries)
+}
+
+// Files that `Unpack` moves to where a `Watch` before it matches them would be processed again
+fn check_moves(steps: &[Step], entries: &[Entry]) -> Result<(), PipelineError> {
+    let is = |i: usize, ty: DataType| entries[i].ty == ty;
+    for w in (0..steps.len()).filter(|&w| is(w, DataType::of::<Watch>())) {
+        // the steps after the watch
+        let mut reached = vec![false; steps.len()];
+        let mut next = steps[w].next.clone();
+        while let Some(n) = next.pop() {
+            if !reached[n] {
+                reached[n] = true;
+                next.extend(&steps[n].next);
+            }
+        }
+
+        for u in (0..steps.len()).filter(|&u| reached[u] && is(u, DataType::of::<Unpack>())) {
+            for dir in move_dirs(&steps[u].args) {
+                if matches_moved(&steps[w].args, &dir) {
+                    let msg = format!(
+                        "{} moves files to `{}`, where {} on line {} picks them up again",
+                        steps[u].describe(u),
+                        dir.display(),
+                        steps[w].describe(w),
+                        steps[w].line
+                    );
+                    return Err(PipelineError::new(steps[u].line, msg));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Pipeline {
+    pub fn parse(src: &str, registry: &Registry) -> Result<Self, PipelineError> {
+        Self::build(parse(src)?, registry)
+    }
+
+    pub fn build(steps: Vec<Step>, registry: &Registry) -> Result<Self, PipelineError> {
+        let entries = resolve(&steps, registry)?;
+        let order = order(&steps)?;
+
+        // Sources and the error sink are not part of the node list, so map step indices to
+        // node indices
+        let mut index = vec![0; steps.len()];
+        let mut count = 0;
+        for &i in &order {
+            if entries[i].kind != Kind::Start && !steps[i].on_error {
+                index[i] = count;
+                count += 1;
+            }
+        }
+
+        let mut steps: Vec<_> = steps.into_iter().map(Some).collect();
+        let mut sources = vec![];
+        let mut nodes = vec![];
+        let mut errors = None;
+        for i in order {
+            let Step {
+                line,
+                id,
+                name,
+                args,
+                on_error,
+                next,
+                threads,
+                capacity,
+                ..
+            } = steps[i].take().unwrap();
+            let entry = entries[i];
+            let next = next.iter().map(|&n| index[n]).collect::<Vec<_>>();
+            let stage = entry.build(args).map_err(|e| {
+                let msg = format!("cannot create `{}`: {}", name, e);
+                PipelineError::new(line, msg)
+            })?;
+
+            // name the stage in the statistics after its id, or its position
+            let name = id.unwrap_or_else(|| format!("{} {}", i + 1, name));
+            let threads = threads.unwrap_or_else(default_threads);
+            let capacity = capacity.unwrap_or(DEFAULT_CAPACITY);
+            match stage {
+                Stage::Start(start) => sources.push(Source {
+                    name,
+                    start,
+                    next: next[0],
+                    capacity,
+                }),
+                Stage::Close(close) if on_error => {
+                    errors = Some(Arc::new(Errors(close)) as Arc<dyn ErrorSink>)
+                }
+                stage => nodes.push(Node {
+                    name,
+                    stage,
+                    next,
+                    threads,
+                    capacity,
+                }),
+            }
+        }
+
+        Ok(Self {
+            sources,
+            nodes,
+            errors,
+        })
+    }
+
+    // Run every step on its own threads, see `Flow`, and wait for the sinks to finish. Fails
+    // with the first sink that cannot be finished, all sinks are finished anyway.
+    pub fn run(self, stats: &Stats) -> Result<(), Error> {
+        let Self {
+            sources,
+            nodes,
+            errors,
+        } = self;
+
+        let mut inputs: Vec<Vec<Flow<AnyData>>> = nodes.iter().map(|_| vec![]).collect();
+        for source in sources {
+            let Source {
+                name,
+                start,
+                next,
+                capacity,
+            } = source;
+            let stage = stats.stage(&name);
+            let start = move || start.start();
+            let flow = Flow::start_with(stage, start, errors.clone(), capacity, stats);
+            inputs[next].push(flow);
+        }
+
+        let mut executors = vec![];
+        for (i, node) in nodes.into_iter().enumerate() {
+            let stage = stats.stage(&node.name);
+            let flows = std::mem::take(&mut inputs[i]);
+            let flow = Flow::merge(flows, node.capacity).expect("every node has an input");
+
+            match node.stage {
+                Stage::Transform(t) => {
+                    let transform = move |i| t.transform(i);
+                    let flow = flow.transform_with(stage, transform, node.threads, node.capacity);
+                    inputs[node.next[0]].push(flow);
+                }
+                Stage::Junction(j) => {
+                    let split = move |i| j.split(i);
+                    let branches = flow.split_with(stage, split, node.next.len(), node.capacity);
+                    for (flow, &n) in branches.into_iter().zip(&node.next) {
+                        inputs[n].push(flow);
+                    }
+                }
+                Stage::Close(c) => {
+                    let c = Arc::new(c);
+                    let finish = c.clone();
+                    let close = move |i| c.close(i);
+                    let finish = move || finish.finish();
+                    executors.push(flow.close_with(stage, close, finish, node.threads));
+                }
+                Stage::Start(_) => unreachable!(),
+            }
+        }
+
+        let mut result = Ok(());
+        for executor in executors {
+            result = result.and(executor.join());
+        }
+
+        // all sinks are complete, finish the error sink, which receives their failures
+        if let Some(errors) = errors {
+            let finished = errors.finish();
+            if let Err(e) = &finished {
+                log::error!("cannot finish the error sink: {}", e);
+            }
+            result = result.and(finished);
+        }
+        result
+    }
+}
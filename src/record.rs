@@ -0,0 +1,294 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::transformers::{key_value, CheckContains};
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+// A CSV record that carries the header of its file, so fields can be addressed by name.
+// Records of the same file share the header.
+#[derive(Clone)]
+pub struct Record {
+    headers: Arc<csv::StringRecord>,
+    values: csv::StringRecord,
+}
+
+impl Record {
+    pub fn new(headers: Arc<csv::StringRecord>, values: csv::StringRecord) -> Self {
+        Self { headers, values }
+    }
+
+    pub fn headers(&self) -> &csv::StringRecord {
+        &self.headers
+    }
+
+    pub fn values(&self) -> &csv::StringRecord {
+        &self.values
+    }
+
+    pub fn into_values(self) -> csv::StringRecord {
+        self.values
+    }
+
+    // Position of a column by name, or by number for files without a header
+    pub fn index(&self, column: &str) -> Option<usize> {
+        self.headers
+            .iter()
+            .position(|h| h == column)
+            .or_else(|| column.parse().ok())
+            .filter(|&i| i < self.values.len())
+    }
+
+    pub fn get(&self, column: &str) -> Option<&str> {
+        self.index(column).and_then(|i| self.values.get(i))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+// Records without a header, columns are addressed by number
+impl From<csv::StringRecord> for Record {
+    fn from(values: csv::StringRecord) -> Self {
+        Self::new(Arc::new(csv::StringRecord::new()), values)
+    }
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = (0..self.values.len()).map(|i| match self.headers.get(i) {
+            Some(name) => name.to_string(),
+            None => i.to_string(),
+        });
+        f.debug_map()
+            .entries(names.zip(self.values.iter()))
+            .finish()
+    }
+}
+
+impl CheckContains for Record {
+    fn contains(&self, needle: &str) -> bool {
+        self.values.contains(needle)
+    }
+}
+
+fn columns(name: &str, args: Vec<String>) -> Result<Vec<String>> {
+    if args.is_empty() {
+        return Err(Error::Args(format!("{} needs column names", name)));
+    }
+    Ok(args)
+}
+
+// Keep the given columns, in the given order
+pub struct Select {
+    columns: Vec<String>,
+}
+
+impl TryFrom<Vec<String>> for Select {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        Ok(Self {
+            columns: columns("Select", args)?,
+        })
+    }
+}
+
+impl Transform for Select {
+    type Input = Record;
+    type Output = Record;
+    type Iter = std::option::IntoIter<FlowFile<Record>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { data, meta } = input;
+
+        let mut values =
+            csv::StringRecord::with_capacity(data.values.as_slice().len(), self.columns.len());
+        for column in &self.columns {
+            match data.get(column) {
+                Some(value) => values.push_field(value),
+                None => {
+                    let raw = format!("{:?}", data);
+                    meta.fail_with_data(Error::UnknownColumn(column.clone()), raw);
+                    return None.into_iter();
+                }
+            }
+        }
+
+        // headers of files without a header row are numbers, which are not valid anymore
+        let headers = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                if data.headers.iter().any(|h| h == c) {
+                    c.clone()
+                } else {
+                    i.to_string()
+                }
+            })
+            .collect();
+
+        let data = Record::new(Arc::new(headers), values);
+        Some(FlowFile { data, meta }).into_iter()
+    }
+}
+
+// Rename columns, given as `old=new` arguments
+pub struct Rename {
+    names: Vec<(String, String)>,
+}
+
+impl TryFrom<Vec<String>> for Rename {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let names = columns("Rename", args)?
+            .iter()
+            .map(|arg| key_value(arg))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { names })
+    }
+}
+
+impl Transform for Rename {
+    type Input = Record;
+    type Output = Record;
+    type Iter = std::iter::Once<FlowFile<Record>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { mut data, meta } = input;
+
+        let headers = data
+            .headers
+            .iter()
+            .map(|h| match self.names.iter().find(|(old, _)| old == h) {
+                Some((_, new)) => new.as_str(),
+                None => h,
+            })
+            .collect();
+        data.headers = Arc::new(headers);
+
+        std::iter::once(FlowFile { data, meta })
+    }
+}
+
+// Named access to the fields of an item, for transformers and junctions that select on a field
+pub trait Field {
+    fn field(&self, name: &str) -> Option<Cow<'_, str>>;
+}
+
+impl Field for Record {
+    fn field(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.get(name).map(Cow::Borrowed)
+    }
+}
+
+// Only pass the items whose field has the given value
+pub struct FieldEquals<A> {
+    field: String,
+    value: String,
+    marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for FieldEquals<A> {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        match args.as_slice() {
+            [field, value] => Ok(Self {
+                field: field.clone(),
+                value: value.clone(),
+                marker: PhantomData,
+            }),
+            _ => Err(Error::Args("FieldEquals needs a field and a value".into())),
+        }
+    }
+}
+
+impl<A: Field + Send + Sync + 'static> Transform for FieldEquals<A> {
+    type Input = A;
+    type Output = A;
+    type Iter = std::option::IntoIter<FlowFile<A>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let matches = input.data.field(&self.field).as_deref() == Some(self.value.as_str());
+        Some(input).filter(|_| matches).into_iter()
+    }
+}
+
+// Copy a field to an attribute, named after the field unless a name is given, e.g. to partition
+// on it. Items without the field are passed on as they are.
+pub struct FieldToAttribute<A> {
+    field: String,
+    attribute: String,
+    marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for FieldToAttribute<A> {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let (field, attribute) = match args.as_slice() {
+            [field] => (field, field),
+            [field, attribute] => (field, attribute),
+            _ => {
+                return Err(Error::Args(
+                    "FieldToAttribute needs a field and optionally an attribute name".into(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            field: field.clone(),
+            attribute: attribute.clone(),
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<A: Field + Send + Sync + 'static> Transform for FieldToAttribute<A> {
+    type Input = A;
+    type Output = A;
+    type Iter = std::iter::Once<FlowFile<A>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { data, mut meta } = input;
+        if let Some(value) = data.field(&self.field) {
+            meta.set_attribute(&self.attribute, value.into_owned());
+        }
+        std::iter::once(FlowFile { data, meta })
+    }
+}
+
+// Drop the header, for steps that take plain CSV records
+pub struct Values {}
+
+impl From<Vec<String>> for Values {
+    fn from(_args: Vec<String>) -> Self {
+        Self {}
+    }
+}
+
+impl Transform for Values {
+    type Input = Record;
+    type Output = csv::StringRecord;
+    type Iter = std::iter::Once<FlowFile<csv::StringRecord>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { data, meta } = input;
+        std::iter::once(FlowFile {
+            data: data.into_values(),
+            meta,
+        })
+    }
+}
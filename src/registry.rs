@@ -0,0 +1,445 @@
+use crate::archive::*;
+#[cfg(feature = "parquet")]
+use crate::columnar::WriteParquet;
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::join::CsvInnerJoin;
+#[cfg(feature = "json")]
+use crate::json::*;
+use crate::junctions::*;
+use crate::partition::PartitionedWrite;
+use crate::record::*;
+use crate::rolling::RollingWrite;
+use crate::text::{WriteCsv, WriteLines};
+use crate::transformers::*;
+use crate::watch::Watch;
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::path::PathBuf;
+
+pub type AnyData = Box<dyn Any + Send>;
+pub type AnyIter = BoxIter<AnyData>;
+
+type Reader = Box<dyn Read + Send + Sync>;
+
+#[derive(Copy, Clone, Debug)]
+pub struct DataType {
+    id: TypeId,
+    name: &'static str,
+}
+
+impl DataType {
+    pub fn of<T: 'static>() -> Self {
+        Self {
+            id: TypeId::of::<T>(),
+            name: std::any::type_name::<T>(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // The type name without module paths, e.g. `Box<dyn Read + Send + Sync>`
+    pub fn short_name(&self) -> String {
+        short_type_name(self.name)
+    }
+}
+
+impl PartialEq for DataType {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+pub trait DynStart: Send + Sync {
+    fn start(self: Box<Self>) -> AnyIter;
+}
+
+pub trait DynTransform: Send + Sync {
+    fn transform(&self, input: FlowFile<AnyData>) -> AnyIter;
+}
+
+pub trait DynClose: Send + Sync {
+    fn close(&self, input: FlowFile<AnyData>);
+    fn finish(&self) -> Result<()>;
+}
+
+pub trait DynJunction: Send + Sync {
+    // Junctions inspect their input by reference, but the concrete type can only be recovered
+    // from the box by value. Hence the flow file is handed back together with the branch.
+    fn split(&self, input: FlowFile<AnyData>) -> (u8, FlowFile<AnyData>);
+}
+
+struct Erased<T>(T);
+
+fn upcast<T: Send + 'static>(input: FlowFile<T>) -> FlowFile<AnyData> {
+    let FlowFile { data, meta } = input;
+    FlowFile {
+        data: Box::new(data),
+        meta,
+    }
+}
+
+fn downcast<T: 'static>(input: FlowFile<AnyData>) -> FlowFile<T> {
+    let FlowFile { data, meta } = input;
+    let data = data
+        .downcast()
+        .expect("types are checked when building the pipeline");
+    FlowFile { data: *data, meta }
+}
+
+impl<T> DynStart for Erased<T>
+where
+    T: StartTransform + Send + Sync + 'static,
+    T::Output: Send + 'static,
+    T::Iter: 'static,
+{
+    fn start(self: Box<Self>) -> AnyIter {
+        Box::new(self.0.start().map(upcast))
+    }
+}
+
+impl<T> DynTransform for Erased<T>
+where
+    T: Transform + Send + Sync + 'static,
+    T::Input: 'static,
+    T::Output: Send + 'static,
+    T::Iter: 'static,
+{
+    fn transform(&self, input: FlowFile<AnyData>) -> AnyIter {
+        Box::new(self.0.transform(downcast(input)).map(upcast))
+    }
+}
+
+impl<T> DynClose for Erased<T>
+where
+    T: CloseTransform + Send + Sync + 'static,
+    T::Input: 'static,
+{
+    fn close(&self, input: FlowFile<AnyData>) {
+        self.0.close(downcast(input))
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.0.finish()
+    }
+}
+
+impl<T> DynJunction for Erased<T>
+where
+    T: Junction + Send + Sync + 'static,
+    T::Input: Send + 'static,
+{
+    fn split(&self, input: FlowFile<AnyData>) -> (u8, FlowFile<AnyData>) {
+        let input = downcast::<T::Input>(input);
+        (self.0.split(&input), upcast(input))
+    }
+}
+
+pub enum Stage {
+    Start(Box<dyn DynStart>),
+    Transform(Box<dyn DynTransform>),
+    Close(Box<dyn DynClose>),
+    Junction(Box<dyn DynJunction>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+    Start,
+    Transform,
+    Close,
+    Junction,
+}
+
+#[derive(Copy, Clone)]
+pub struct Entry {
+    pub kind: Kind,
+    // the transformer, for the generated code
+    pub ty: DataType,
+    pub input: Option<DataType>,
+    pub output: Option<DataType>,
+    build: fn(Vec<String>) -> Result<Stage>,
+}
+
+impl Entry {
+    pub fn build(&self, args: Vec<String>) -> Result<Stage> {
+        (self.build)(args)
+    }
+}
+
+fn build_start<T>(args: Vec<String>) -> Result<Stage>
+where
+    T: StartTransform + Send + Sync + 'static,
+    <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+    T::Output: Send + 'static,
+    T::Iter: 'static,
+{
+    let t = T::try_from(args).map_err(Into::into)?;
+    Ok(Stage::Start(Box::new(Erased(t))))
+}
+
+fn build_transform<T>(args: Vec<String>) -> Result<Stage>
+where
+    T: Transform + Send + Sync + 'static,
+    <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+    T::Input: 'static,
+    T::Output: Send + 'static,
+    T::Iter: 'static,
+{
+    let t = T::try_from(args).map_err(Into::into)?;
+    Ok(Stage::Transform(Box::new(Erased(t))))
+}
+
+fn build_close<T>(args: Vec<String>) -> Result<Stage>
+where
+    T: CloseTransform + Send + Sync + 'static,
+    <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+    T::Input: 'static,
+{
+    let t = T::try_from(args).map_err(Into::into)?;
+    Ok(Stage::Close(Box::new(Erased(t))))
+}
+
+fn build_junction<T>(args: Vec<String>) -> Result<Stage>
+where
+    T: Junction + Send + Sync + 'static,
+    <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+    T::Input: Send + 'static,
+{
+    let t = T::try_from(args).map_err(Into::into)?;
+    Ok(Stage::Junction(Box::new(Erased(t))))
+}
+
+pub struct Registry {
+    entries: HashMap<&'static str, Vec<Entry>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, name: &'static str, entry: Entry) {
+        self.entries.entry(name).or_default().push(entry);
+    }
+
+    pub fn register_start<T>(&mut self, name: &'static str)
+    where
+        T: StartTransform + Send + Sync + 'static,
+        <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+        T::Output: Send + 'static,
+        T::Iter: 'static,
+    {
+        let entry = Entry {
+            kind: Kind::Start,
+            ty: DataType::of::<T>(),
+            input: None,
+            output: Some(DataType::of::<T::Output>()),
+            build: build_start::<T>,
+        };
+        self.insert(name, entry);
+    }
+
+    pub fn register_transform<T>(&mut self, name: &'static str)
+    where
+        T: Transform + Send + Sync + 'static,
+        <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+        T::Input: 'static,
+        T::Output: Send + 'static,
+        T::Iter: 'static,
+    {
+        let entry = Entry {
+            kind: Kind::Transform,
+            ty: DataType::of::<T>(),
+            input: Some(DataType::of::<T::Input>()),
+            output: Some(DataType::of::<T::Output>()),
+            build: build_transform::<T>,
+        };
+        self.insert(name, entry);
+    }
+
+    pub fn register_close<T>(&mut self, name: &'static str)
+    where
+        T: CloseTransform + Send + Sync + 'static,
+        <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+        T::Input: 'static,
+    {
+        let entry = Entry {
+            kind: Kind::Close,
+            ty: DataType::of::<T>(),
+            input: Some(DataType::of::<T::Input>()),
+            output: None,
+            build: build_close::<T>,
+        };
+        self.insert(name, entry);
+    }
+
+    pub fn register_junction<T>(&mut self, name: &'static str)
+    where
+        T: Junction + Send + Sync + 'static,
+        <T as TryFrom<Vec<String>>>::Error: Into<Error>,
+        T::Input: Send + 'static,
+    {
+        let entry = Entry {
+            kind: Kind::Junction,
+            ty: DataType::of::<T>(),
+            input: Some(DataType::of::<T::Input>()),
+            output: Some(DataType::of::<T::Input>()),
+            build: build_junction::<T>,
+        };
+        self.insert(name, entry);
+    }
+
+    pub fn entries(&self, name: &str) -> &[Entry] {
+        self.entries
+            .get(name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    // Names of the transformers that turn `from` into any of the `to` types
+    pub fn converters(&self, from: DataType, to: &[DataType]) -> Vec<&'static str> {
+        let mut names: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entries)| {
+                entries.iter().any(|e| {
+                    e.kind == Kind::Transform
+                        && e.input == Some(from)
+                        && to.iter().any(|t| e.output == Some(*t))
+                })
+            })
+            .map(|(&name, _)| name)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Find the variant of a (possibly generic) transformer that accepts the given input type.
+    // Start transformers take no input and are found by passing `None`.
+    pub fn lookup(&self, name: &str, input: Option<DataType>) -> Option<&Entry> {
+        self.entries(name).iter().find(|e| e.input == input)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut r = Self::new();
+
+        r.register_start::<Glob>("Glob");
+        r.register_start::<Watch>("Watch");
+
+        r.register_transform::<Unpack>("Unpack");
+        r.register_transform::<Lines>("Lines");
+        r.register_transform::<Untar<PathBuf>>("Untar");
+        r.register_transform::<Untar<Reader>>("Untar");
+        #[cfg(feature = "zip")]
+        r.register_transform::<Unzip<PathBuf>>("Unzip");
+        #[cfg(feature = "zip")]
+        r.register_transform::<Unzip<Reader>>("Unzip");
+        r.register_transform::<Csv>("Csv");
+        r.register_transform::<CsvRecords>("CsvRecords");
+        r.register_transform::<CsvInnerJoin>("CsvInnerJoin");
+        r.register_transform::<Select>("Select");
+        r.register_transform::<Rename>("Rename");
+        r.register_transform::<FieldEquals<Record>>("FieldEquals");
+        r.register_transform::<FieldToAttribute<Record>>("FieldToAttribute");
+        r.register_transform::<Values>("Values");
+        r.register_transform::<Contains<String>>("Contains");
+        r.register_transform::<Contains<Vec<u8>>>("Contains");
+        r.register_transform::<Contains<csv::StringRecord>>("Contains");
+        r.register_transform::<Contains<Record>>("Contains");
+        r.register_transform::<ToString<String>>("ToString");
+        r.register_transform::<ToString<PathBuf>>("ToString");
+        r.register_transform::<ToString<Vec<u8>>>("ToString");
+        r.register_transform::<ToString<csv::StringRecord>>("ToString");
+        r.register_transform::<ToString<Record>>("ToString");
+        r.register_transform::<Identity<String>>("Identity");
+        r.register_transform::<Identity<PathBuf>>("Identity");
+        r.register_transform::<Identity<Vec<u8>>>("Identity");
+        r.register_transform::<Identity<Reader>>("Identity");
+        r.register_transform::<Identity<csv::StringRecord>>("Identity");
+        r.register_transform::<Identity<Record>>("Identity");
+        r.register_transform::<SetAttribute<String>>("SetAttribute");
+        r.register_transform::<SetAttribute<PathBuf>>("SetAttribute");
+        r.register_transform::<SetAttribute<Vec<u8>>>("SetAttribute");
+        r.register_transform::<SetAttribute<Reader>>("SetAttribute");
+        r.register_transform::<SetAttribute<csv::StringRecord>>("SetAttribute");
+        r.register_transform::<SetAttribute<Record>>("SetAttribute");
+        r.register_transform::<AttributeEquals<String>>("AttributeEquals");
+        r.register_transform::<AttributeEquals<PathBuf>>("AttributeEquals");
+        r.register_transform::<AttributeEquals<Vec<u8>>>("AttributeEquals");
+        r.register_transform::<AttributeEquals<Reader>>("AttributeEquals");
+        r.register_transform::<AttributeEquals<csv::StringRecord>>("AttributeEquals");
+        r.register_transform::<AttributeEquals<Record>>("AttributeEquals");
+
+        r.register_close::<Write>("Write");
+        r.register_close::<RollingWrite>("RollingWrite");
+        r.register_close::<PartitionedWrite>("PartitionedWrite");
+        r.register_close::<WriteLines>("WriteLines");
+        r.register_close::<WriteCsv<csv::StringRecord>>("WriteCsv");
+        r.register_close::<WriteCsv<Record>>("WriteCsv");
+        r.register_close::<StdOut>("StdOut");
+        r.register_close::<Nullify<String>>("Nullify");
+        r.register_close::<Nullify<PathBuf>>("Nullify");
+        r.register_close::<Nullify<Vec<u8>>>("Nullify");
+        r.register_close::<Nullify<Reader>>("Nullify");
+        r.register_close::<Nullify<csv::StringRecord>>("Nullify");
+        r.register_close::<Nullify<Record>>("Nullify");
+        r.register_close::<Nullify<Failure>>("Nullify");
+        r.register_close::<DeadLetter>("DeadLetter");
+
+        r.register_junction::<SplitByExt<String>>("SplitByExt");
+        r.register_junction::<SplitByExt<PathBuf>>("SplitByExt");
+        r.register_junction::<SplitByExt<Vec<u8>>>("SplitByExt");
+        r.register_junction::<SplitByExt<Reader>>("SplitByExt");
+        r.register_junction::<SplitByExt<csv::StringRecord>>("SplitByExt");
+        r.register_junction::<SplitByExt<Record>>("SplitByExt");
+        r.register_junction::<SplitByAttribute<String>>("SplitByAttribute");
+        r.register_junction::<SplitByAttribute<PathBuf>>("SplitByAttribute");
+        r.register_junction::<SplitByAttribute<Vec<u8>>>("SplitByAttribute");
+        r.register_junction::<SplitByAttribute<Reader>>("SplitByAttribute");
+        r.register_junction::<SplitByAttribute<csv::StringRecord>>("SplitByAttribute");
+        r.register_junction::<SplitByAttribute<Record>>("SplitByAttribute");
+        r.register_junction::<SplitByField<Record>>("SplitByField");
+
+        #[cfg(feature = "json")]
+        register_json(&mut r);
+        #[cfg(feature = "parquet")]
+        {
+            r.register_close::<WriteParquet<csv::StringRecord>>("WriteParquet");
+            r.register_close::<WriteParquet<Record>>("WriteParquet");
+        }
+
+        r
+    }
+}
+
+#[cfg(feature = "json")]
+fn register_json(r: &mut Registry) {
+    use serde_json::Value;
+
+    r.register_transform::<JsonLines>("JsonLines");
+    r.register_transform::<ToJson<Value>>("ToJson");
+    r.register_transform::<ToJson<csv::StringRecord>>("ToJson");
+    r.register_transform::<ToJson<Record>>("ToJson");
+    r.register_transform::<Contains<Value>>("Contains");
+    r.register_transform::<ToString<Value>>("ToString");
+    r.register_transform::<Identity<Value>>("Identity");
+    r.register_transform::<SetAttribute<Value>>("SetAttribute");
+    r.register_transform::<AttributeEquals<Value>>("AttributeEquals");
+    r.register_transform::<FieldEquals<Value>>("FieldEquals");
+    r.register_transform::<FieldToAttribute<Value>>("FieldToAttribute");
+
+    r.register_close::<Nullify<Value>>("Nullify");
+
+    r.register_junction::<SplitByExt<Value>>("SplitByExt");
+    r.register_junction::<SplitByAttribute<Value>>("SplitByAttribute");
+    r.register_junction::<SplitByField<Value>>("SplitByField");
+}
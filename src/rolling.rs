@@ -0,0 +1,245 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::transformers::{bytes, duration, options, TarGz};
+
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+// The date of a time as 2021-06-30, in UTC
+pub(crate) fn date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    // civil date of days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = secs.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + (m <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+// Fills in `{date}` (2021-06-30), `{time}` (142501) in UTC and `{seq}`
+fn file_name(template: &str, now: SystemTime, seq: u64) -> String {
+    let t = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        % 86400;
+
+    template
+        .replace("{date}", &date(now))
+        .replace(
+            "{time}",
+            &format!("{:02}{:02}{:02}", t / 3600, t / 60 % 60, t % 60),
+        )
+        .replace("{seq}", &seq.to_string())
+}
+
+// The file being written, under a temporary name until it is complete
+struct Part {
+    archive: TarGz,
+    partial: PathBuf,
+    path: PathBuf,
+    bytes: u64,
+    items: u64,
+    opened: Instant,
+}
+
+impl Part {
+    fn complete(self) -> Result<()> {
+        self.archive.finish()?;
+        std::fs::rename(&self.partial, &self.path)?;
+        log::info!("completed {}", self.path.display());
+        Ok(())
+    }
+}
+
+struct Rolling {
+    template: String,
+    max_bytes: Option<u64>,
+    max_items: Option<u64>,
+    interval: Option<Duration>,
+    current: Option<Part>,
+    seq: u64,
+}
+
+impl Rolling {
+    fn is_full(&self, part: &Part) -> bool {
+        self.max_bytes.is_some_and(|max| part.bytes >= max)
+            || self.max_items.is_some_and(|max| part.items >= max)
+            || self.interval.is_some_and(|i| part.opened.elapsed() >= i)
+    }
+
+    // Complete the current file if it is full
+    fn rotate(&mut self) -> Result<()> {
+        match self.current.take() {
+            Some(part) if self.is_full(&part) => part.complete(),
+            part => {
+                self.current = part;
+                Ok(())
+            }
+        }
+    }
+
+    fn current(&mut self) -> Result<&mut Part> {
+        if self.current.is_none() {
+            // names of earlier runs are skipped, also of partial files left by a crash
+            let now = SystemTime::now();
+            let (path, partial) = loop {
+                let path = PathBuf::from(file_name(&self.template, now, self.seq));
+                let partial = partial_path(&path);
+                self.seq += 1;
+                if !path.exists() && !partial.exists() {
+                    break (path, partial);
+                }
+            };
+
+            self.current = Some(Part {
+                archive: TarGz::create(&partial)?,
+                partial,
+                path,
+                bytes: 0,
+                items: 0,
+                opened: Instant::now(),
+            });
+        }
+
+        Ok(self.current.as_mut().unwrap())
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+// Writes items to a series of tar.gz archives, like `Write`, starting a new one when the current
+// one is full. The first argument is the name template, e.g. `out-{date}-{seq}.tar.gz`, with
+// `{seq}` numbering the files. Options are given as `key=value`:
+// max_bytes=64M (of data, before compression), max_items=N and interval=1h.
+//
+// Archives are written as `name.partial` and renamed once complete, so readers of the output
+// directory never see partial files. The last archive is completed when the flow ends.
+pub struct RollingWrite {
+    state: Arc<Mutex<Rolling>>,
+}
+
+impl TryFrom<Vec<String>> for RollingWrite {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        if args.is_empty() || args[0].contains('=') {
+            return Err(Error::Args("RollingWrite needs a name template".into()));
+        }
+        let template = args.remove(0);
+        if !template.contains("{seq}") {
+            return Err(Error::Args(
+                "the RollingWrite template needs a `{seq}`".into(),
+            ));
+        }
+
+        let options = options(
+            "RollingWrite",
+            &args,
+            &["max_bytes", "max_items", "interval"],
+        )?;
+        let max_bytes = options.get("max_bytes").map(|v| bytes(v)).transpose()?;
+        let max_items = options
+            .get("max_items")
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| Error::Args(format!("`max_items` needs a number, got `{}`", v)))
+            })
+            .transpose()?;
+        let interval = options.get("interval").map(|v| duration(v)).transpose()?;
+
+        let state = Arc::new(Mutex::new(Rolling {
+            template,
+            max_bytes: max_bytes.map(|b| b as u64),
+            max_items,
+            interval,
+            current: None,
+            seq: 0,
+        }));
+
+        // complete files when their time is up, also when no items arrive
+        if let Some(interval) = interval {
+            let weak = Arc::downgrade(&state);
+            std::thread::spawn(move || expire(weak, interval));
+        }
+
+        Ok(Self { state })
+    }
+}
+
+fn expire(state: Weak<Mutex<Rolling>>, interval: Duration) {
+    let tick = interval.min(Duration::from_secs(1));
+    loop {
+        std::thread::sleep(tick);
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.lock().unwrap();
+        if let Err(e) = state.rotate() {
+            log::error!("cannot complete output file: {}", e);
+        }
+    }
+}
+
+impl CloseTransform for RollingWrite {
+    type Input = String;
+
+    fn close(&self, input: FlowFile<Self::Input>) {
+        let FlowFile { data, meta } = input;
+
+        // the time of the current file may be up before the expiry thread noticed
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = state.rotate() {
+            log::error!("cannot complete output file: {}", e);
+        }
+
+        let part = match state.current() {
+            Ok(part) => part,
+            Err(e) => return meta.fail(e),
+        };
+        match part.archive.append(&meta, data.as_bytes()) {
+            Ok(()) => {
+                part.bytes += data.len() as u64;
+                part.items += 1;
+            }
+            Err(e) => meta.fail(e),
+        }
+
+        // complete a full file right away, rather than with the next item
+        if let Err(e) = state.rotate() {
+            log::error!("cannot complete output file: {}", e);
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.state.lock().unwrap().current.take() {
+            Some(part) => part.complete(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RollingWrite {
+    fn drop(&mut self) {
+        if let Err(e) = CloseTransform::finish(self) {
+            log::error!("cannot complete output file: {}", e);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+use crate::record::Record;
+use crate::transformers::{boolean, bytes, options, single_byte, source_file, Output};
+
+use std::convert::TryFrom;
+use std::io::Write as _;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Order {
+    // as the items arrive from the workers
+    Arrival,
+    // grouped by source file and in line order, which needs all items in memory until the end,
+    // up to a limit
+    Source,
+}
+
+impl Order {
+    fn parse(value: Option<&String>) -> Result<Self> {
+        match value.map(String::as_str) {
+            None | Some("arrival") => Ok(Order::Arrival),
+            Some("source") => Ok(Order::Source),
+            Some(other) => Err(Error::Args(format!(
+                "`order` needs arrival or source, got `{}`",
+                other
+            ))),
+        }
+    }
+}
+
+struct State {
+    // taken when the file is complete
+    out: Option<Output>,
+    started: bool,
+    // items waiting for the end of the flow, with their source file and line
+    buffered: Vec<(String, u64, Vec<u8>)>,
+    size: usize,
+}
+
+// Bytes of lines kept for `order=source`, unless the sink says otherwise
+const BUFFER: usize = 256 << 20;
+
+// A text file that is written line by line, for `WriteLines` and `WriteCsv`
+struct TextFile {
+    order: Order,
+    buffer: usize,
+    state: Mutex<State>,
+}
+
+impl TextFile {
+    fn create(path: &Path, order: Order, buffer: usize) -> Result<Self> {
+        Ok(Self {
+            order,
+            buffer,
+            state: Mutex::new(State {
+                out: Some(Output::create(path)?),
+                started: false,
+                buffered: vec![],
+                size: 0,
+            }),
+        })
+    }
+
+    // Write a line, after `first` for the first line of the file
+    fn write(
+        &self,
+        meta: &FlowFileMeta,
+        line: Vec<u8>,
+        first: impl FnOnce() -> Option<Vec<u8>>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let out = match state.out.as_mut() {
+            Some(out) => out,
+            None => return Err(std::io::Error::other("the file is already complete").into()),
+        };
+
+        if !state.started {
+            state.started = true;
+            if let Some(first) = first() {
+                out.write_all(&first)?;
+            }
+        }
+
+        match self.order {
+            Order::Arrival => out.write_all(&line)?,
+            Order::Source => {
+                // the lines are only written at the end, a flow without an end is refused
+                // rather than kept in memory
+                if state.size + line.len() > self.buffer {
+                    let msg = format!(
+                        "order=source keeps more than {} bytes, use a larger `buffer` or order=arrival",
+                        self.buffer
+                    );
+                    return Err(std::io::Error::other(msg).into());
+                }
+                state.size += line.len();
+                let number = match meta.attribute(LINE) {
+                    Some(Attribute::UInt(n)) => *n,
+                    _ => 0,
+                };
+                state
+                    .buffered
+                    .push((source_file(meta).to_string(), number, line));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut out = match state.out.take() {
+            Some(out) => out,
+            None => return Ok(()),
+        };
+
+        // stable, so items of the same line keep their order
+        let mut buffered = std::mem::take(&mut state.buffered);
+        state.size = 0;
+        buffered.sort_by(|(s1, n1, _), (s2, n2, _)| s1.cmp(s2).then(n1.cmp(n2)));
+        for (_, _, line) in buffered {
+            out.write_all(&line)?;
+        }
+        out.finish()
+    }
+}
+
+impl Drop for TextFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::error!("cannot complete the output file: {}", e);
+        }
+    }
+}
+
+fn path(name: &str, args: &mut Vec<String>) -> Result<String> {
+    if args.is_empty() || args[0].contains('=') {
+        return Err(Error::Args(format!("{} needs an output path", name)));
+    }
+    Ok(args.remove(0))
+}
+
+// Writes every item as a line of a text file, gzip compressed when the name ends in `.gz`.
+// The first argument is the path, the option order=arrival (default) or order=source orders the
+// lines. Lines are never interleaved, with `source` they are also grouped by source file and in
+// their original order, but only written when the flow ends. They are kept in memory up to
+// buffer=256M, lines beyond that fail.
+pub struct WriteLines {
+    file: TextFile,
+}
+
+impl TryFrom<Vec<String>> for WriteLines {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        let path = path("WriteLines", &mut args)?;
+        let options = options("WriteLines", &args, &["order", "buffer"])?;
+        let order = Order::parse(options.get("order"))?;
+        let buffer = options.get("buffer").map(|v| bytes(v)).transpose()?;
+
+        Ok(Self {
+            file: TextFile::create(path.as_ref(), order, buffer.unwrap_or(BUFFER))?,
+        })
+    }
+}
+
+impl CloseTransform for WriteLines {
+    type Input = String;
+
+    fn close(&self, input: FlowFile<Self::Input>) {
+        let FlowFile { data, meta } = input;
+
+        let mut line = data.into_bytes();
+        if line.last() != Some(&b'\n') {
+            line.push(b'\n');
+        }
+        if let Err(e) = self.file.write(&meta, line, || None) {
+            meta.fail(e);
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.file.finish()
+    }
+}
+
+// Writes CSV records to a file, gzip compressed when the name ends in `.gz`. The first argument
+// is the path, options are given as `key=value`:
+// delimiter=; (or `tab`), headers=false to leave out the header row of named records, and
+// order=arrival (default) or source with buffer=256M, as for `WriteLines`.
+pub struct WriteCsv<A> {
+    file: TextFile,
+    delimiter: u8,
+    headers: bool,
+    marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for WriteCsv<A> {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        let path = path("WriteCsv", &mut args)?;
+        let keys = ["delimiter", "headers", "order", "buffer"];
+        let options = options("WriteCsv", &args, &keys)?;
+        let delimiter = match options.get("delimiter") {
+            Some(value) => single_byte("delimiter", value)?,
+            None => b',',
+        };
+        let headers = match options.get("headers") {
+            Some(value) => boolean("headers", value)?,
+            None => true,
+        };
+        let order = Order::parse(options.get("order"))?;
+        let buffer = options.get("buffer").map(|v| bytes(v)).transpose()?;
+
+        Ok(Self {
+            file: TextFile::create(path.as_ref(), order, buffer.unwrap_or(BUFFER))?,
+            delimiter,
+            headers,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<A> WriteCsv<A> {
+    fn line(&self, record: &csv::StringRecord) -> Result<Vec<u8>> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .from_writer(vec![]);
+        writer.write_record(record)?;
+        writer.into_inner().map_err(|e| Error::Io(e.into_error()))
+    }
+}
+
+impl<A: Into<Record> + Send + Sync + 'static> CloseTransform for WriteCsv<A> {
+    type Input = A;
+
+    fn close(&self, input: FlowFile<Self::Input>) {
+        let FlowFile { data, meta } = input;
+        let record: Record = data.into();
+
+        // the header row is taken from the first record
+        let result = self.line(record.values()).and_then(|line| {
+            self.file.write(&meta, line, || {
+                let headers = record.headers();
+                if !self.headers || headers.is_empty() {
+                    return None;
+                }
+                self.line(headers).ok()
+            })
+        });
+        if let Err(e) = result {
+            let raw = record.values().iter().collect::<Vec<_>>().join(",");
+            meta.fail_with_data(e, raw);
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.file.finish()
+    }
+}
@@ -1,14 +1,19 @@
+use crate::checkpoint::{Checkpoint, Progress, State};
+use crate::error::{Error, Result};
 use crate::framework::*;
+use crate::record::Record;
 
 use flate2::{read::GzDecoder, write::GzEncoder};
 use glob::glob;
 
-use std::collections::{hash_map, HashMap};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write as _};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 pub struct Glob {
     patterns: Vec<String>,
@@ -16,12 +21,13 @@ pub struct Glob {
 
 impl StartTransform for Glob {
     type Output = PathBuf;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
+    type Iter = BoxIter<Self::Output>;
 
     fn start(self) -> Self::Iter {
-        self.patterns
+        let paths = self
+            .patterns
             .into_iter()
-            .flat_map(|pat| glob(&pat).expect("bad glob pattern"))
+            .flat_map(|pat| glob(&pat).into_iter().flatten())
             .flat_map(|glob| match glob {
                 Ok(path) => Some(path),
                 Err(e) => {
@@ -29,91 +35,416 @@ impl StartTransform for Glob {
                     None
                 }
             })
-            .map(FlowFile::new)
+            .map(FlowFile::new);
+
+        Box::new(paths)
     }
 }
 
-impl From<Vec<String>> for Glob {
-    fn from(args: Vec<String>) -> Self {
-        Self { patterns: args }
+impl TryFrom<Vec<String>> for Glob {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        // validate the patterns up front, so `start` can ignore pattern errors
+        for pat in &args {
+            glob::Pattern::new(pat)?;
+        }
+
+        Ok(Self { patterns: args })
     }
 }
 
-pub struct Unpack {}
+// Parse a `key=value` argument
+pub(crate) fn key_value(arg: &str) -> Result<(String, String)> {
+    match arg.find('=') {
+        Some(pos) => Ok((arg[..pos].to_string(), arg[pos + 1..].to_string())),
+        None => Err(Error::Args(format!("expected key=value, got `{}`", arg))),
+    }
+}
 
-impl From<Vec<String>> for Unpack {
-    fn from(_args: Vec<String>) -> Self {
-        Self {}
+// Parse the `key=value` options of a transformer, rejecting unknown keys
+pub(crate) fn options(
+    name: &str,
+    args: &[String],
+    keys: &[&str],
+) -> Result<HashMap<String, String>> {
+    args.iter()
+        .map(|arg| {
+            let (key, value) = key_value(arg)?;
+            if !keys.contains(&key.as_str()) {
+                let msg = format!(
+                    "{} has no option `{}`, expected one of {:?}",
+                    name, key, keys
+                );
+                return Err(Error::Args(msg));
+            }
+            Ok((key, value))
+        })
+        .collect()
+}
+
+// A size like `4096`, `64k`, `256M` or `1G`
+pub(crate) fn bytes(value: &str) -> Result<usize> {
+    let (number, shift) = match value.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&value[..i], 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&value[..i], 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&value[..i], 30),
+        _ => (value, 0),
+    };
+    number
+        .parse::<usize>()
+        .map(|n| n << shift)
+        .map_err(|_| Error::Args(format!("expected a size like `64M`, got `{}`", value)))
+}
+
+// A duration like `500ms`, `30s`, `15m`, `1h` or `1d`
+pub(crate) fn duration(value: &str) -> Result<Duration> {
+    let units = [
+        ("ms", 1),
+        ("s", 1000),
+        ("m", 60_000),
+        ("h", 3_600_000),
+        ("d", 86_400_000),
+    ];
+    units
+        .iter()
+        .find_map(|(unit, millis)| {
+            let n = value.strip_suffix(unit)?.parse::<u64>().ok()?;
+            Some(Duration::from_millis(n * millis))
+        })
+        .ok_or_else(|| Error::Args(format!("expected a duration like `15m`, got `{}`", value)))
+}
+
+// What to do with a source file once it is processed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum FileAction {
+    #[default]
+    Keep,
+    // move into a directory, relative to the directory of the file
+    Move(PathBuf),
+    Delete,
+    // append a suffix to the file name
+    Rename(String),
+}
+
+impl FileAction {
+    // Parses `keep`, `move:dir`, `delete` or `rename:suffix`
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.find(':').map(|pos| (&s[..pos], &s[pos + 1..])) {
+            None if s == "keep" => Ok(FileAction::Keep),
+            None if s == "delete" => Ok(FileAction::Delete),
+            Some(("move", dir)) if !dir.is_empty() => Ok(FileAction::Move(dir.into())),
+            Some(("rename", suffix)) if !suffix.is_empty() => {
+                Ok(FileAction::Rename(suffix.to_string()))
+            }
+            _ => Err(Error::Args(format!(
+                "expected keep, move:dir, delete or rename:suffix, got `{}`",
+                s
+            ))),
+        }
+    }
+
+    pub fn apply(&self, path: &Path) -> std::io::Result<()> {
+        match self {
+            FileAction::Keep => Ok(()),
+            FileAction::Delete => std::fs::remove_file(path),
+            FileAction::Move(dir) => {
+                let dir = path.parent().unwrap_or_else(|| Path::new("")).join(dir);
+                std::fs::create_dir_all(&dir)?;
+                let name = path.file_name().unwrap_or_default();
+                move_file(path, &dir.join(name))
+            }
+            FileAction::Rename(suffix) => {
+                let mut name = path.file_name().unwrap_or_default().to_os_string();
+                name.push(suffix);
+                std::fs::rename(path, path.with_file_name(name))
+            }
+        }
+    }
+}
+
+// Rename, or copy and remove when the target is on another file system
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+// Compression formats with their magic bytes and file extension
+const CODECS: &[(&str, &[u8], &str)] = &[
+    ("gzip", &[0x1f, 0x8b], ".gz"),
+    ("zstd", &[0x28, 0xb5, 0x2f, 0xfd], ".zst"),
+    ("bzip2", b"BZh", ".bz2"),
+    ("xz", &[0xfd, b'7', b'z', b'X', b'Z', 0], ".xz"),
+    ("lz4", &[0x04, 0x22, 0x4d, 0x18], ".lz4"),
+];
+
+// `BZh` is followed by the block size `1` to `9`, and the magic of the first block, or of the
+// end of an empty stream
+fn is_bzip2(head: &[u8]) -> bool {
+    const BLOCK: [u8; 6] = [0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+    const END: [u8; 6] = [0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+
+    head.len() >= 10
+        && matches!(head[3], b'1'..=b'9')
+        && (head[4..10] == BLOCK || head[4..10] == END)
+}
+
+// Detect the compression by the magic bytes, or by the extension for files that are too short
+// to tell
+fn codec(head: &[u8], path: &Path) -> Option<&'static str> {
+    let name = path.to_string_lossy();
+    CODECS
+        .iter()
+        .find(|(codec, magic, _)| head.starts_with(magic) && (*codec != "bzip2" || is_bzip2(head)))
+        .or_else(|| {
+            CODECS
+                .iter()
+                .find(|(_, magic, ext)| head.len() < magic.len() && name.ends_with(ext))
+        })
+        .map(|(codec, _, _)| *codec)
+}
+
+pub(crate) fn decode(
+    file: File,
+    path: &Path,
+) -> Result<(Box<dyn Read + Send + Sync>, Option<&'static str>)> {
+    let mut reader = BufReader::new(file);
+    let codec = codec(reader.fill_buf()?, path);
+
+    let reader: Box<dyn Read + Send + Sync> = match codec {
+        None => Box::new(reader),
+        Some("gzip") => Box::new(GzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Some("zstd") => Box::new(zstd::Decoder::with_buffer(reader)?),
+        #[cfg(feature = "bzip2")]
+        Some("bzip2") => Box::new(bzip2::read::BzDecoder::new(reader)),
+        #[cfg(feature = "xz")]
+        Some("xz") => Box::new(xz2::read::XzDecoder::new(reader)),
+        #[cfg(feature = "lz4")]
+        Some("lz4") => Box::new(lz4::Decoder::new(reader)?),
+        Some(codec) => return Err(Error::Codec(codec)),
+    };
+
+    Ok((reader, codec))
+}
+
+// Compressed files are decoded, see `CODECS`, and their codec is set as attribute.
+// With `checkpoint=path`, files that were processed by an earlier run are skipped.
+// With `on_success=..` and `on_failure=..`, files are moved, deleted or renamed once processed,
+// see `FileAction::parse`.
+#[derive(Default)]
+pub struct Unpack {
+    checkpoint: Option<Arc<Checkpoint>>,
+    on_success: Arc<FileAction>,
+    on_failure: Arc<FileAction>,
+}
+
+impl TryFrom<Vec<String>> for Unpack {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let keys = ["checkpoint", "on_success", "on_failure"];
+        let options = options("Unpack", &args, &keys)?;
+        let checkpoint = options
+            .get("checkpoint")
+            .map(Checkpoint::open)
+            .transpose()?;
+        let action = |key| {
+            let action = options.get(key).map(|s| FileAction::parse(s)).transpose()?;
+            Ok::<_, Error>(Arc::new(action.unwrap_or_default()))
+        };
+
+        Ok(Self {
+            checkpoint,
+            on_success: action("on_success")?,
+            on_failure: action("on_failure")?,
+        })
     }
 }
 
+// The directories the `Unpack` arguments move files to, invalid options are left to `try_from`
+pub(crate) fn move_dirs(args: &[String]) -> Vec<PathBuf> {
+    args.iter()
+        .filter_map(|arg| key_value(arg).ok())
+        .filter(|(key, _)| key == "on_success" || key == "on_failure")
+        .filter_map(|(_, value)| match FileAction::parse(&value) {
+            Ok(FileAction::Move(dir)) => Some(dir),
+            _ => None,
+        })
+        .collect()
+}
+
 impl Transform for Unpack {
     type Input = PathBuf;
     type Output = Box<dyn Read + Send + Sync>;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
+    type Iter = BoxIter<Self::Output>;
 
     fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
         let FlowFile { data, mut meta } = input;
 
-        let file = File::open(&data).unwrap();
-        log::debug!("now processing {}", &data.to_string_lossy());
-
-        let reader = if matches!(data.to_str(), Some(p) if p.ends_with(".gz")) {
-            Box::new(GzDecoder::new(file)) as Box<dyn Read + Send + Sync>
-        } else {
-            Box::new(file) as _
-        };
-
         // set file path as source
         meta.add_source(&data.to_string_lossy());
+        meta.set_attribute(PATH, data.to_string_lossy().into_owned());
+        if let Some(name) = data.file_name() {
+            meta.set_attribute(FILENAME, name.to_string_lossy().into_owned());
+        }
 
-        let flow_file = FlowFile { data: reader, meta };
-        let iter = std::iter::once(flow_file);
+        let source = meta.source().to_string();
+        if matches!(&self.checkpoint, Some(c) if c.is_done(&source)) {
+            log::debug!("skipping {}, it was processed before", source);
+            return Box::new(std::iter::empty());
+        }
 
-        let data_clone = data.clone();
-        CloseableIter::new(
-            iter,
-            move || log::debug!("processing success {:?}", data_clone),
-            move || log::debug!("processing failure {:?}", data),
-        )
+        log::debug!("now processing {}", &data.to_string_lossy());
+        let file = File::open(&data);
+        if let Some(metadata) = file.as_ref().ok().and_then(|f| f.metadata().ok()) {
+            meta.set_attribute(FILE_SIZE, metadata.len());
+            if let Ok(mtime) = metadata.modified() {
+                meta.set_attribute(MTIME, mtime);
+            }
+        }
+
+        // the file is complete once the last item read from it has been processed
+        let (data_clone, path) = (data.clone(), data.clone());
+        let checkpoint = self.checkpoint.clone();
+        let (on_success, on_failure) = (self.on_success.clone(), self.on_failure.clone());
+        meta.on_complete(
+            move || {
+                log::debug!("processing success {:?}", data_clone);
+                if let Some(checkpoint) = &checkpoint {
+                    checkpoint.complete(&source);
+                }
+                if let Err(e) = on_success.apply(&data_clone) {
+                    log::error!("cannot {:?} {:?}: {}", on_success, data_clone, e);
+                }
+            },
+            move || {
+                log::debug!("processing failure {:?}", path);
+                if let Err(e) = on_failure.apply(&path) {
+                    log::error!("cannot {:?} {:?}: {}", on_failure, path, e);
+                }
+            },
+        );
+
+        let reader = match file.map_err(Error::from).and_then(|f| decode(f, &data)) {
+            Ok((reader, codec)) => {
+                if let Some(codec) = codec {
+                    meta.set_attribute(CODEC, codec);
+                }
+                Some(reader)
+            }
+            Err(e) => {
+                meta.fail(e);
+                None
+            }
+        };
+
+        Box::new(reader.map(|data| FlowFile { data, meta }).into_iter())
     }
 }
 
-pub struct Lines {}
+// With `checkpoint=path`, a source is resumed after the last line an earlier run processed
+#[derive(Default)]
+pub struct Lines {
+    checkpoint: Option<Arc<Checkpoint>>,
+}
 
-impl From<Vec<String>> for Lines {
-    fn from(_args: Vec<String>) -> Self {
-        Self {}
+impl TryFrom<Vec<String>> for Lines {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let options = options("Lines", &args, &["checkpoint"])?;
+        let checkpoint = options
+            .get("checkpoint")
+            .map(Checkpoint::open)
+            .transpose()?;
+
+        Ok(Self { checkpoint })
     }
 }
 
 impl Transform for Lines {
     type Input = Box<dyn Read + Send + Sync>;
     type Output = String;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
+    type Iter = BoxIter<Self::Output>;
 
     fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
         let FlowFile { data, meta } = input;
 
-        BufReader::new(data)
-            .lines()
-            .enumerate()
-            .flat_map(move |(i, lr)| match lr {
-                Ok(l) => {
-                    let mut my_meta = meta.clone();
-                    my_meta.add_source(&format!(":{}", i));
-
-                    Some(FlowFile {
-                        data: l,
-                        meta: my_meta,
-                    })
+        // Lines before `skip` were processed by an earlier run, the lines that are emitted record
+        // their progress once processed
+        let (progress, skip) = match &self.checkpoint {
+            Some(c) => {
+                let skip = match c.state(meta.source()) {
+                    Some(State::Done) => return Box::new(std::iter::empty()),
+                    Some(State::Line(n)) => n,
+                    None => 0,
+                };
+                (Some(Progress::new(c.clone(), meta.source(), skip)), skip)
+            }
+            None => (None, 0),
+        };
+
+        // Read raw lines instead of `BufRead::lines`, so invalid UTF-8 can be passed on to the
+        // error sink. Stop at the first I/O error, the reader is unusable after that.
+        let mut reader = BufReader::new(data);
+        let mut done = false;
+        let lines = std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let mut line = vec![];
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => None,
+                Ok(_) => {
+                    if line.last() == Some(&b'\n') {
+                        line.pop();
+                        if line.last() == Some(&b'\r') {
+                            line.pop();
+                        }
+                    }
+                    Some(Ok(line))
                 }
                 Err(e) => {
-                    log::error!("Exception in Lines: {:?}", e);
-                    meta.mark_failed();
+                    done = true;
+                    Some(Err(e))
+                }
+            }
+        });
+
+        let lines = lines.enumerate().flat_map(move |(i, lr)| {
+            if (i as u64) < skip {
+                return None;
+            }
+
+            let mut my_meta = line_meta(&meta, i as u64);
+            if let Some(progress) = &progress {
+                // a failed line is not recorded, so a resumed run retries it
+                let progress = progress.clone();
+                my_meta.on_complete(move || progress.complete(i as u64), || ());
+            }
+
+            match lr.map(String::from_utf8) {
+                Ok(Ok(l)) => Some(FlowFile {
+                    data: l,
+                    meta: my_meta,
+                }),
+                Ok(Err(e)) => {
+                    let error = Error::Utf8(e.utf8_error().to_string());
+                    my_meta.fail_with_data(error, String::from_utf8_lossy(e.as_bytes()).into());
                     None
                 }
-            })
+                Err(e) => {
+                    my_meta.fail(e);
+                    None
+                }
+            }
+        });
+
+        Box::new(lines)
     }
 }
 
@@ -158,13 +489,85 @@ impl<A> From<Vec<String>> for Identity<A> {
 impl<A: Send + Sync + 'static> Transform for Identity<A> {
     type Input = A;
     type Output = A;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
+    type Iter = std::iter::Once<FlowFile<A>>;
 
     fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
         std::iter::once(input)
     }
 }
 
+// Set custom attributes, given as `key=value` arguments
+pub struct SetAttribute<A> {
+    attributes: Vec<(String, String)>,
+    marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for SetAttribute<A> {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let attributes = args
+            .iter()
+            .map(|arg| key_value(arg))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            attributes,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<A: Send + Sync + 'static> Transform for SetAttribute<A> {
+    type Input = A;
+    type Output = A;
+    type Iter = std::iter::Once<FlowFile<A>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let FlowFile { data, mut meta } = input;
+        for (key, value) in &self.attributes {
+            meta.set_attribute(key, value.as_str());
+        }
+        std::iter::once(FlowFile { data, meta })
+    }
+}
+
+// Only pass the items whose attribute `key` has the given value
+pub struct AttributeEquals<A> {
+    key: String,
+    value: String,
+    marker: PhantomData<A>,
+}
+
+impl<A> TryFrom<Vec<String>> for AttributeEquals<A> {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        match args.as_slice() {
+            [key, value] => Ok(Self {
+                key: key.clone(),
+                value: value.clone(),
+                marker: PhantomData,
+            }),
+            _ => Err(Error::Args(
+                "AttributeEquals needs a key and a value".into(),
+            )),
+        }
+    }
+}
+
+impl<A: Send + Sync + 'static> Transform for AttributeEquals<A> {
+    type Input = A;
+    type Output = A;
+    type Iter = std::option::IntoIter<FlowFile<A>>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        let matches =
+            matches!(input.meta.attribute(&self.key), Some(v) if v.to_string() == self.value);
+        Some(input).filter(|_| matches).into_iter()
+    }
+}
+
 pub struct ToString<A> {
     marker: PhantomData<A>,
 }
@@ -186,7 +589,7 @@ impl<A> From<Vec<String>> for ToString<A> {
 impl<A: Send + Sync + std::fmt::Debug + 'static> Transform for ToString<A> {
     type Input = A;
     type Output = String;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
+    type Iter = std::iter::Once<FlowFile<String>>;
 
     fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
         let FlowFile { data, meta } = input;
@@ -211,68 +614,300 @@ impl CloseTransform for StdOut {
     }
 }
 
-pub struct Csv {}
+// Options are given as `key=value`:
+// delimiter=; (or `tab`), quote=' (or `none`), headers=false, flexible=true, comment=#,
+// trim=all (or `headers`, `fields`, `none`)
+pub struct Csv {
+    delimiter: u8,
+    quote: Option<u8>,
+    headers: bool,
+    flexible: bool,
+    comment: Option<u8>,
+    trim: csv::Trim,
+}
 
-impl From<Vec<String>> for Csv {
-    fn from(_args: Vec<String>) -> Self {
-        Self {}
+impl Default for Csv {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: Some(b'"'),
+            headers: true,
+            flexible: false,
+            comment: None,
+            trim: csv::Trim::None,
+        }
+    }
+}
+
+pub(crate) fn single_byte(key: &str, value: &str) -> Result<u8> {
+    match value {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if value.len() == 1 => Ok(value.as_bytes()[0]),
+        _ => Err(Error::Args(format!(
+            "`{}` needs a single ASCII character, got `{}`",
+            key, value
+        ))),
+    }
+}
+
+pub(crate) fn boolean(key: &str, value: &str) -> Result<bool> {
+    value
+        .parse()
+        .map_err(|_| Error::Args(format!("`{}` needs true or false, got `{}`", key, value)))
+}
+
+impl TryFrom<Vec<String>> for Csv {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let keys = [
+            "delimiter",
+            "quote",
+            "headers",
+            "flexible",
+            "comment",
+            "trim",
+        ];
+        let mut csv = Self::default();
+
+        for (key, value) in options("Csv", &args, &keys)? {
+            let value = value.as_str();
+            match key.as_str() {
+                "delimiter" => csv.delimiter = single_byte(&key, value)?,
+                "quote" if value == "none" => csv.quote = None,
+                "quote" => csv.quote = Some(single_byte(&key, value)?),
+                "headers" => csv.headers = boolean(&key, value)?,
+                "flexible" => csv.flexible = boolean(&key, value)?,
+                "comment" => csv.comment = Some(single_byte(&key, value)?),
+                _ => {
+                    csv.trim = match value {
+                        "none" => csv::Trim::None,
+                        "headers" => csv::Trim::Headers,
+                        "fields" => csv::Trim::Fields,
+                        "all" => csv::Trim::All,
+                        _ => {
+                            let msg = format!(
+                                "`trim` needs none, headers, fields or all, got `{}`",
+                                value
+                            );
+                            return Err(Error::Args(msg));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(csv)
     }
 }
 
 impl Transform for Csv {
     type Input = Box<dyn Read + Send + Sync>;
     type Output = csv::StringRecord;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
+    type Iter = BoxIter<Self::Output>;
 
     fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        self.read(input, |_, record| record)
+    }
+}
+
+impl Csv {
+    pub(crate) fn reader<R: Read>(&self, data: R) -> csv::Reader<R> {
+        csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .quoting(self.quote.is_some())
+            .quote(self.quote.unwrap_or(b'"'))
+            .has_headers(self.headers)
+            .flexible(self.flexible)
+            .comment(self.comment)
+            .trim(self.trim)
+            .from_reader(data)
+    }
+
+    // The header row, or an empty header when the file has none
+    pub(crate) fn headers<R: Read>(
+        &self,
+        reader: &mut csv::Reader<R>,
+    ) -> csv::Result<Arc<csv::StringRecord>> {
+        let headers = reader.headers()?;
+        if self.headers {
+            Ok(Arc::new(headers.clone()))
+        } else {
+            Ok(Arc::new(csv::StringRecord::new()))
+        }
+    }
+
+    // Read the records of a file, `record` combines the header of the file with each record
+    fn read<T, F>(&self, input: FlowFile<Box<dyn Read + Send + Sync>>, record: F) -> BoxIter<T>
+    where
+        F: Fn(&Arc<csv::StringRecord>, csv::StringRecord) -> T + Send + 'static,
+        T: Send + 'static,
+    {
         let FlowFile { data, meta } = input;
 
-        csv::Reader::from_reader(data)
-            .into_records()
-            .enumerate()
-            .flat_map(move |(i, r)| match r {
-                Ok(v) => {
-                    let mut my_meta = meta.clone();
-                    my_meta.add_source(&format!(":{}", i));
-
-                    Some(FlowFile {
-                        data: v,
-                        meta: my_meta,
-                    })
+        let mut reader = self.reader(data);
+        let headers = match self.headers(&mut reader) {
+            Ok(headers) => headers,
+            Err(e) => {
+                meta.fail(e);
+                return Box::new(std::iter::empty());
+            }
+        };
+
+        // Read byte records, so the raw fields of a bad row can be passed on to the error sink
+        let delimiter = self.delimiter as char;
+        let records = std::iter::from_fn(move || {
+            let mut record = csv::ByteRecord::new();
+            match reader.read_byte_record(&mut record) {
+                Ok(true) => Some(Ok(record)),
+                Ok(false) => None,
+                Err(e) => Some(Err((e, record))),
+            }
+        });
+
+        let records = records.enumerate().flat_map(move |(i, r)| {
+            let my_meta = line_meta(&meta, i as u64);
+
+            match r.map(csv::StringRecord::from_byte_record) {
+                Ok(Ok(v)) => Some(FlowFile {
+                    data: record(&headers, v),
+                    meta: my_meta,
+                }),
+                Ok(Err(e)) => {
+                    let error = Error::Utf8(e.utf8_error().to_string());
+                    let raw = raw_record(&e.into_byte_record(), delimiter);
+                    my_meta.fail_with_data(error, raw);
+                    None
                 }
-                Err(e) => {
-                    log::error!("Exception in Csv: {:?}", e);
-                    meta.mark_failed();
+                Err((e, record)) => {
+                    my_meta.fail_with_data(e, raw_record(&record, delimiter));
                     None
                 }
-            })
+            }
+        });
+
+        Box::new(records)
     }
 }
 
-pub struct Write {
-    builder: Mutex<tar::Builder<GzEncoder<BufWriter<File>>>>,
+// Like `Csv`, with the same options, but the records carry the header of the file
+#[derive(Default)]
+pub struct CsvRecords {
+    csv: Csv,
 }
 
-impl From<Vec<String>> for Write {
-    fn from(args: Vec<String>) -> Self {
-        Self::new(&args[0])
+impl TryFrom<Vec<String>> for CsvRecords {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        Ok(Self {
+            csv: Csv::try_from(args)?,
+        })
     }
 }
 
-impl Write {
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        let file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(path.as_ref())
-            .unwrap();
+impl CsvRecords {
+    pub(crate) fn csv(&self) -> &Csv {
+        &self.csv
+    }
+}
+
+impl Transform for CsvRecords {
+    type Input = Box<dyn Read + Send + Sync>;
+    type Output = Record;
+    type Iter = BoxIter<Self::Output>;
+
+    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
+        self.csv.read(input, |headers, values| {
+            Record::new(headers.clone(), values)
+        })
+    }
+}
+
+// The meta of a line of a file, as `data.csv:12`
+pub(crate) fn line_meta(meta: &FlowFileMeta, line: u64) -> FlowFileMeta {
+    let mut meta = meta.clone();
+    meta.add_source(&format!(":{}", line));
+    meta.set_attribute(LINE, line);
+    meta
+}
+
+// The source of an item without the line number, i.e. the file it was read from
+pub(crate) fn source_file(meta: &FlowFileMeta) -> &str {
+    let source = meta.source();
+    match meta.attribute(LINE) {
+        Some(_) => source.rsplit_once(':').map_or(source, |(file, _)| file),
+        None => source,
+    }
+}
+
+fn raw_record(record: &csv::ByteRecord, delimiter: char) -> String {
+    let fields: Vec<_> = record.iter().map(String::from_utf8_lossy).collect();
+    fields.join(&delimiter.to_string())
+}
+
+// A gzipped tar archive being written, with an entry per item
+pub(crate) struct TarGz {
+    builder: tar::Builder<GzEncoder<BufWriter<File>>>,
+}
+
+impl TarGz {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
         let buffered = BufWriter::new(file);
         let gz = GzEncoder::new(buffered, flate2::Compression::default());
-        let tar = tar::Builder::new(gz);
+        let builder = tar::Builder::new(gz);
+
+        Ok(Self { builder })
+    }
+
+    pub(crate) fn append(&mut self, meta: &FlowFileMeta, data: &[u8]) -> std::io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        if let Some(Attribute::Time(mtime)) = meta.attribute(MTIME) {
+            let secs = mtime
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            header.set_mtime(secs.as_secs());
+        }
+        header.set_cksum();
+
+        self.builder.append_data(&mut header, meta.source(), data)
+    }
+
+    // Complete the archive and sync it to disk
+    pub(crate) fn finish(self) -> Result<()> {
+        let gz = self.builder.into_inner()?;
+        let buffered = gz.finish()?;
+        let file = buffered.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+// Writes items as entries of a tar.gz archive. The archive is completed and synced to disk when
+// the flow ends, and otherwise when the sink is dropped.
+pub struct Write {
+    // taken when the archive is complete
+    builder: Mutex<Option<TarGz>>,
+}
 
-        let builder = Mutex::new(tar);
+impl TryFrom<Vec<String>> for Write {
+    type Error = Error;
 
-        Self { builder }
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        match args.first() {
+            Some(path) => Self::new(path),
+            None => Err(Error::Args("Write needs an output path".into())),
+        }
+    }
+}
+
+impl Write {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let builder = Mutex::new(Some(TarGz::create(path.as_ref())?));
+
+        Ok(Self { builder })
     }
 }
 
@@ -281,15 +916,175 @@ impl CloseTransform for Write {
 
     fn close(&self, input: FlowFile<Self::Input>) {
         let FlowFile { data, meta } = input;
-        let data = data.as_bytes();
-
-        let mut header = tar::Header::new_gnu();
-        header.set_size(data.len() as u64);
-        header.set_cksum();
 
         let mut ar = self.builder.lock().unwrap();
-        ar.append_data(&mut header, meta.source(), data).unwrap();
+        let result = match ar.as_mut() {
+            Some(ar) => ar.append(&meta, data.as_bytes()),
+            None => Err(std::io::Error::other("the archive is already complete")),
+        };
+        if let Err(e) = result {
+            meta.fail(e);
+        }
     }
+
+    fn finish(&self) -> Result<()> {
+        match self.builder.lock().unwrap().take() {
+            Some(ar) => ar.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Write {
+    fn drop(&mut self) {
+        if let Err(e) = CloseTransform::finish(self) {
+            log::error!("cannot complete the archive: {}", e);
+        }
+    }
+}
+
+// An output file, gzip compressed when its name ends in `.gz`
+pub(crate) enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Output {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let buffered = BufWriter::new(file);
+
+        if path.extension() == Some("gz".as_ref()) {
+            let gz = GzEncoder::new(buffered, flate2::Compression::default());
+            Ok(Output::Gzip(gz))
+        } else {
+            Ok(Output::Plain(buffered))
+        }
+    }
+
+    // Write the gzip trailer, flush and sync to disk. Nothing should be written afterwards.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        let buffered = match self {
+            Output::Plain(buffered) => buffered,
+            Output::Gzip(gz) => {
+                gz.try_finish()?;
+                gz.get_mut()
+            }
+        };
+        buffered.flush()?;
+        buffered.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+impl std::io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Plain(w) => w.write(buf),
+            Output::Gzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(w) => w.flush(),
+            Output::Gzip(w) => w.flush(),
+        }
+    }
+}
+
+// Dead letter sink, writes one JSON object per failure:
+// {"source": "data.csv:12", "error": "...", "data": "1,2", "attributes": {"line": "12"}}
+pub struct DeadLetter {
+    // taken when the file is complete
+    out: Mutex<Option<Output>>,
+}
+
+impl TryFrom<Vec<String>> for DeadLetter {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        match args.first() {
+            Some(path) => Self::new(path),
+            None => Err(Error::Args("DeadLetter needs an output path".into())),
+        }
+    }
+}
+
+impl DeadLetter {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            out: Mutex::new(Some(Output::create(path.as_ref())?)),
+        })
+    }
+}
+
+impl CloseTransform for DeadLetter {
+    type Input = Failure;
+
+    fn close(&self, input: FlowFile<Self::Input>) {
+        let FlowFile { data, meta } = input;
+
+        let data_json = match &data.data {
+            Some(d) => json_string(d),
+            None => "null".to_string(),
+        };
+        let attributes: Vec<_> = meta
+            .attributes()
+            .iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(&v.to_string())))
+            .collect();
+        let line = format!(
+            "{{\"source\":{},\"error\":{},\"data\":{},\"attributes\":{{{}}}}}\n",
+            json_string(meta.source()),
+            json_string(&data.error.to_string()),
+            data_json,
+            attributes.join(",")
+        );
+
+        let mut out = self.out.lock().unwrap();
+        let result = match out.as_mut() {
+            Some(out) => out.write_all(line.as_bytes()),
+            None => Err(std::io::Error::other("the file is already complete")),
+        };
+        if let Err(e) = result {
+            // the dead letter sink itself cannot report to an error sink
+            log::error!("Cannot write failure of {}: {}", meta.source(), e);
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.out.lock().unwrap().take() {
+            Some(mut out) => out.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DeadLetter {
+    fn drop(&mut self) {
+        if let Err(e) = CloseTransform::finish(self) {
+            log::error!("cannot complete the dead letter file: {}", e);
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
 
 pub struct Contains<R> {
@@ -297,12 +1092,18 @@ pub struct Contains<R> {
     _marker: PhantomData<R>,
 }
 
-impl<R> From<Vec<String>> for Contains<R> {
-    fn from(mut args: Vec<String>) -> Self {
-        Self {
+impl<R> TryFrom<Vec<String>> for Contains<R> {
+    type Error = Error;
+
+    fn try_from(mut args: Vec<String>) -> Result<Self> {
+        if args.is_empty() {
+            return Err(Error::Args("Contains needs a search string".into()));
+        }
+
+        Ok(Self {
             needle: args.remove(0),
             _marker: PhantomData,
-        }
+        })
     }
 }
 
@@ -317,7 +1118,7 @@ impl CheckContains for String {
 }
 impl CheckContains for Vec<u8> {
     fn contains(&self, needle: &str) -> bool {
-        std::str::from_utf8(&self)
+        std::str::from_utf8(self)
             .map(|s| s.contains(needle))
             .unwrap_or(false)
     }
@@ -331,74 +1132,10 @@ impl CheckContains for csv::StringRecord {
 impl<S: CheckContains + Send> Transform for Contains<S> {
     type Input = S;
     type Output = S;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
+    type Iter = std::option::IntoIter<FlowFile<S>>;
 
     fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
         let contains = input.data.contains(&self.needle);
-        std::iter::once(input).filter(move |_| contains)
-    }
-}
-
-pub struct CsvInnerJoin {}
-
-impl From<Vec<String>> for CsvInnerJoin {
-    fn from(_args: Vec<String>) -> Self {
-        Self {}
-    }
-}
-
-struct CsvInnerJoinIter<I> {
-    csv: I,
-    pending: Option<HashMap<String, FlowFile<csv::StringRecord>>>,
-    flush: Option<hash_map::IntoIter<String, FlowFile<csv::StringRecord>>>,
-}
-
-impl<I: Iterator<Item = FlowFile<csv::StringRecord>>> Iterator for CsvInnerJoinIter<I> {
-    type Item = FlowFile<csv::StringRecord>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if self.pending.is_some() {
-                if let Some(r) = self.csv.next() {
-                    let id = r.data.get(0).unwrap();
-                    let reference = r.data.get(2).unwrap();
-
-                    if !reference.is_empty() {
-                        self.pending
-                            .as_mut()
-                            .unwrap()
-                            .insert(reference.to_string(), r);
-                    } else if let Some(mut earlier) = self.pending.as_mut().unwrap().remove(id) {
-                        earlier.data.push_field(r.data.get(1).unwrap());
-                        return Some(earlier);
-                    } else {
-                        return Some(r);
-                    }
-                } else {
-                    self.flush = Some(self.pending.take().unwrap().into_iter());
-                }
-            }
-
-            if let Some(flush) = &mut self.flush {
-                return flush.next().map(|(_k, v)| v);
-            }
-        }
-    }
-}
-
-impl Transform for CsvInnerJoin {
-    type Input = Box<dyn Read + Send + Sync>;
-    type Output = csv::StringRecord;
-    type Iter = impl Iterator<Item = FlowFile<Self::Output>> + Send;
-
-    fn transform(&self, input: FlowFile<Self::Input>) -> Self::Iter {
-        let csv = Csv {};
-        let csv_iter = csv.transform(input);
-
-        CsvInnerJoinIter {
-            csv: csv_iter,
-            pending: Some(HashMap::new()),
-            flush: None,
-        }
+        Some(input).filter(|_| contains).into_iter()
     }
 }
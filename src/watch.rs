@@ -0,0 +1,387 @@
+use crate::error::{Error, Result};
+use crate::framework::*;
+
+use glob::glob;
+
+use std::collections::{hash_map, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+// Keeps watching the glob patterns and emits every file that appears, once its size and
+// modification time did not change for the settle time, so half-written files are skipped.
+//
+// Arguments are glob patterns, and the options `settle=ms` (default 1000) and `poll=ms`
+// (default 1000). With the `inotify` feature the watched directories are rescanned as soon as
+// something changes, and polling is only a fallback. Patterns with wildcards in directories,
+// like `in/**/*.csv`, have all subdirectories of their base directory watched.
+//
+// A file is emitted once, until it disappears, e.g. because `Unpack` moved it to `done/`. A
+// pipeline that moves files to where the patterns match them again is rejected.
+pub struct Watch {
+    patterns: Vec<String>,
+    settle: Duration,
+    poll: Duration,
+    stop: Arc<AtomicBool>,
+}
+
+// Stops a running `Watch` at its next scan
+#[derive(Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+}
+
+impl Watch {
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.stop.clone())
+    }
+}
+
+impl TryFrom<Vec<String>> for Watch {
+    type Error = Error;
+
+    fn try_from(args: Vec<String>) -> Result<Self> {
+        let mut patterns = vec![];
+        let mut settle = Duration::from_millis(1000);
+        let mut poll = Duration::from_millis(1000);
+
+        for arg in args {
+            let millis = |v: &str| {
+                v.parse()
+                    .map(Duration::from_millis)
+                    .map_err(|_| Error::Args(format!("expected milliseconds, got `{}`", v)))
+            };
+            if let Some(v) = arg.strip_prefix("settle=") {
+                settle = millis(v)?;
+            } else if let Some(v) = arg.strip_prefix("poll=") {
+                poll = millis(v)?;
+            } else {
+                glob::Pattern::new(&arg)?;
+                patterns.push(arg);
+            }
+        }
+
+        if patterns.is_empty() {
+            return Err(Error::Args("Watch needs a pattern".into()));
+        }
+
+        Ok(Self {
+            patterns,
+            settle,
+            poll,
+            stop: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+impl StartTransform for Watch {
+    type Output = PathBuf;
+    type Iter = WatchIter;
+
+    fn start(self) -> Self::Iter {
+        let dirs: Vec<_> = self.patterns.iter().map(|p| base_dir(p)).collect();
+
+        WatchIter {
+            events: events(&dirs),
+            watch: self,
+            pending: HashMap::new(),
+            emitted: HashSet::new(),
+            ready: VecDeque::new(),
+            scanned: false,
+        }
+    }
+}
+
+// Whether a file moved to `dir`, relative to its own directory unless absolute, matches one of
+// the patterns of the `Watch` arguments again
+pub(crate) fn matches_moved(args: &[String], dir: &Path) -> bool {
+    let mut patterns = args
+        .iter()
+        .filter(|a| !a.starts_with("settle=") && !a.starts_with("poll="));
+    patterns.any(|pattern| {
+        let path = Path::new(pattern);
+        if dir.is_absolute() {
+            let name = path.file_name().unwrap_or_default();
+            glob::Pattern::new(pattern).is_ok_and(|p| p.matches_path(&dir.join(name)))
+        } else {
+            // below the directory of the file, only `**` matches it again
+            let below = dir
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            below
+                && path
+                    .parent()
+                    .is_some_and(|p| p.to_string_lossy().contains("**"))
+        }
+    })
+}
+
+// The directory part of a pattern before the first wildcard, and whether the pattern matches
+// in its subdirectories, like `in/**/*.csv`
+fn base_dir(pattern: &str) -> (PathBuf, bool) {
+    let mut dir = PathBuf::new();
+    let mut recursive = false;
+    for component in Path::new(pattern).parent().into_iter().flat_map(Path::iter) {
+        if component.to_string_lossy().contains(&['*', '?', '['][..]) {
+            recursive = true;
+            break;
+        }
+        dir.push(component);
+    }
+
+    if dir.as_os_str().is_empty() {
+        (PathBuf::from("."), recursive)
+    } else {
+        (dir, recursive)
+    }
+}
+
+// Wakes up the iterator on file system events in the watched directories. Subdirectories are
+// watched too when the pattern has a wildcard below the base directory, also those created
+// later on. Dropping it stops the reader thread, which closes the inotify descriptor.
+#[cfg(feature = "inotify")]
+struct Events {
+    rx: Receiver<()>,
+    stop: Arc<AtomicBool>,
+    watches: inotify::Watches,
+    dirs: Arc<std::sync::Mutex<Dirs>>,
+}
+
+// The watched directories, and whether their subdirectories are watched
+#[cfg(feature = "inotify")]
+type Dirs = HashMap<inotify::WatchDescriptor, (PathBuf, bool)>;
+
+#[cfg(feature = "inotify")]
+impl Events {
+    fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<(), std::sync::mpsc::RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+#[cfg(feature = "inotify")]
+impl Drop for Events {
+    fn drop(&mut self) {
+        // removing the watches queues an event, which wakes up the blocked reader
+        self.stop.store(true, Ordering::SeqCst);
+        for (wd, _) in self.dirs.lock().unwrap().drain() {
+            let _ = self.watches.remove(wd);
+        }
+    }
+}
+
+#[cfg(feature = "inotify")]
+fn add_watches(
+    watches: &mut inotify::Watches,
+    dirs: &mut Dirs,
+    dir: &Path,
+    recursive: bool,
+) -> std::io::Result<()> {
+    use inotify::WatchMask;
+
+    let mask = WatchMask::CREATE
+        | WatchMask::MODIFY
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::MOVED_TO
+        | WatchMask::MOVED_FROM
+        | WatchMask::DELETE;
+    let wd = watches.add(dir, mask)?;
+    dirs.insert(wd, (dir.to_path_buf(), recursive));
+
+    if recursive {
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                add_watches(watches, dirs, &entry.path(), true)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "inotify")]
+fn events(dirs: &[(PathBuf, bool)]) -> Option<Events> {
+    use inotify::{EventMask, Inotify};
+    use std::sync::mpsc::{sync_channel, TrySendError};
+
+    let mut watched = HashMap::new();
+    let mut watch = || {
+        let inotify = Inotify::init()?;
+        for (dir, recursive) in dirs {
+            add_watches(&mut inotify.watches(), &mut watched, dir, *recursive)?;
+        }
+        Ok::<_, std::io::Error>(inotify)
+    };
+
+    let mut inotify = match watch() {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            log::warn!("cannot watch {:?}, polling instead: {}", dirs, e);
+            return None;
+        }
+    };
+
+    // a single pending wakeup is enough, the iterator rescans everything
+    let (tx, rx) = sync_channel(1);
+    let stop = Arc::new(AtomicBool::new(false));
+    let watched = Arc::new(std::sync::Mutex::new(watched));
+    let events = Events {
+        rx,
+        stop: stop.clone(),
+        watches: inotify.watches(),
+        dirs: watched.clone(),
+    };
+
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok(events) = inotify.read_events_blocking(&mut buffer) {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+
+            // new subdirectories of recursive directories are watched as well
+            let mut watched = watched.lock().unwrap();
+            let created: Vec<_> = events
+                .filter(|e| e.mask.contains(EventMask::ISDIR))
+                .filter(|e| e.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO))
+                .filter_map(|e| match watched.get(&e.wd) {
+                    Some((parent, true)) => Some(parent.join(e.name?)),
+                    _ => None,
+                })
+                .collect();
+            for dir in created {
+                if let Err(e) = add_watches(&mut inotify.watches(), &mut watched, &dir, true) {
+                    log::warn!("cannot watch {}: {}", dir.display(), e);
+                }
+            }
+            drop(watched);
+
+            if let Err(TrySendError::Disconnected(_)) = tx.try_send(()) {
+                return;
+            }
+        }
+    });
+
+    Some(events)
+}
+
+#[cfg(not(feature = "inotify"))]
+type Events = Receiver<()>;
+
+#[cfg(not(feature = "inotify"))]
+fn events(_dirs: &[(PathBuf, bool)]) -> Option<Events> {
+    None
+}
+
+struct Pending {
+    size: u64,
+    mtime: Option<SystemTime>,
+    since: Instant,
+}
+
+pub struct WatchIter {
+    watch: Watch,
+    events: Option<Events>,
+    pending: HashMap<PathBuf, Pending>,
+    emitted: HashSet<PathBuf>,
+    ready: VecDeque<PathBuf>,
+    scanned: bool,
+}
+
+impl WatchIter {
+    fn scan(&mut self) {
+        let now = Instant::now();
+        let mut present = HashSet::new();
+
+        let paths = self
+            .watch
+            .patterns
+            .iter()
+            .flat_map(|pat| glob(pat).into_iter().flatten())
+            .flatten();
+        for path in paths {
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            present.insert(path.clone());
+            if self.emitted.contains(&path) {
+                continue;
+            }
+
+            let (size, mtime) = (metadata.len(), metadata.modified().ok());
+            match self.pending.entry(path) {
+                hash_map::Entry::Vacant(e) => {
+                    e.insert(Pending {
+                        size,
+                        mtime,
+                        since: now,
+                    });
+                }
+                hash_map::Entry::Occupied(mut e) => {
+                    let p = e.get_mut();
+                    if p.size != size || p.mtime != mtime {
+                        *p = Pending {
+                            size,
+                            mtime,
+                            since: now,
+                        };
+                    } else if now.duration_since(p.since) >= self.watch.settle {
+                        let (path, _) = e.remove_entry();
+                        self.emitted.insert(path.clone());
+                        self.ready.push_back(path);
+                    }
+                }
+            }
+        }
+
+        // forget files that disappeared, so a new file with the same name is picked up
+        self.emitted.retain(|p| present.contains(p));
+        self.pending.retain(|p, _| present.contains(p));
+    }
+
+    fn wait(&self) {
+        // files that are settling are checked again after the settle time
+        let timeout = if self.pending.is_empty() {
+            self.watch.poll
+        } else {
+            self.watch.poll.min(self.watch.settle)
+        };
+
+        match &self.events {
+            Some(events) => {
+                let _ = events.recv_timeout(timeout);
+            }
+            None => std::thread::sleep(timeout),
+        }
+    }
+}
+
+impl Iterator for WatchIter {
+    type Item = FlowFile<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(path) = self.ready.pop_front() {
+                return Some(FlowFile::new(path));
+            }
+            if self.watch.stop.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            if self.scanned {
+                self.wait();
+            }
+            self.scan();
+            self.scanned = true;
+        }
+    }
+}